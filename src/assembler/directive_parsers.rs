@@ -1,6 +1,6 @@
 use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::label_parsers::label_declaration;
-use crate::assembler::operand_parsers::operand;
+use crate::assembler::operand_parsers::directive_operand;
 use crate::assembler::Token;
 use nom::alpha1;
 use nom::types::CompleteStr;
@@ -20,9 +20,9 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
         do_parse!(
             l: opt!(label_declaration) >>
             name: directive_declaration >>
-            o1: opt!(operand) >>
-            o2: opt!(operand) >>
-            o3: opt!(operand) >>
+            o1: opt!(directive_operand) >>
+            o2: opt!(directive_operand) >>
+            o3: opt!(directive_operand) >>
             (
                 AssemblerInstruction{
                     opcode: None,
@@ -0,0 +1,39 @@
+use crate::instruction::Opcode;
+use std::error::Error;
+use std::fmt;
+
+// Errors raised while assembling a program, reported instead of producing wrong bytecode
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssemblerError {
+    // The text could not be parsed as a program
+    ParseError { error: String },
+    // The program goes on with text that is not an instruction or a directive, such as
+    // a string operand outside of a directive
+    UnexpectedInput { line: String },
+    // An operand of a kind the instruction cannot encode
+    InvalidOperand { opcode: Opcode },
+    // An integer that does not fit in the bytes the instruction has for it
+    OperandOutOfRange { opcode: Opcode, value: i32 },
+    // A label used but never declared
+    UnknownLabel { name: String },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::ParseError { error } => write!(f, "parse error: {}", error),
+            AssemblerError::UnexpectedInput { line } => {
+                write!(f, "unexpected input: {}", line)
+            }
+            AssemblerError::InvalidOperand { opcode } => {
+                write!(f, "invalid operand for {:?}", opcode)
+            }
+            AssemblerError::OperandOutOfRange { opcode, value } => {
+                write!(f, "operand {} out of range for {:?}", value, opcode)
+            }
+            AssemblerError::UnknownLabel { name } => write!(f, "unknown label @{}", name),
+        }
+    }
+}
+
+impl Error for AssemblerError {}
@@ -1,9 +1,12 @@
+use crate::assembler::error::AssemblerError;
 use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::opcode_parsers::opcode;
 use crate::assembler::operand_parsers::operand;
 
 use crate::assembler::Token;
+use crate::instruction::Opcode;
 
+use nom::multispace;
 use nom::types::CompleteStr;
 
 use super::symbols::SymbolTable;
@@ -19,12 +22,19 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut results: Vec<u8> = vec![];
 
+        // Directives do not produce code
+        if self.opcode.is_none() {
+            return Ok(results);
+        }
+
+        let mut opcode = Opcode::IGL;
         if let Some(ref token) = self.opcode {
             match token {
                 Token::Op { code } => {
+                    opcode = *code;
                     let b: u8 = *code as u8;
                     results.push(b);
                 }
@@ -34,46 +44,113 @@ impl AssemblerInstruction {
             }
         }
 
-        [&self.operand1, &self.operand2, &self.operand3]
-            .iter()
-            .for_each(|operand| {
-                if let Some(token) = operand {
-                    AssemblerInstruction::extract_operand(token, &mut results, symbols);
-                }
-            });
+        // LOADF takes a float register and a constant, which always makes it 12 bytes long
+        if opcode == Opcode::LOADF
+            && !matches!(
+                (&self.operand1, &self.operand2, &self.operand3),
+                (
+                    Some(Token::FloatRegister { .. }),
+                    Some(Token::IntegerOperand { .. } | Token::FloatOperand { .. }),
+                    None
+                )
+            )
+        {
+            return Err(AssemblerError::InvalidOperand { opcode });
+        }
+
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(token, opcode, &mut results, symbols)?;
+        }
 
         while results.len() < 4 {
             results.push(0_u8);
         }
 
-        results
+        Ok(results)
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    fn extract_operand(
+        t: &Token,
+        opcode: Opcode,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+    ) -> Result<(), AssemblerError> {
         match t {
-            Token::Register { reg_num } => {
+            // Registers come first, so the operand is register number `results.len() - 1`
+            Token::Register { reg_num } | Token::FloatRegister { reg_num } => {
+                let float = matches!(t, Token::FloatRegister { .. });
+                if float != opcode.is_float_register(results.len() - 1) {
+                    return Err(AssemblerError::InvalidOperand { opcode });
+                }
                 results.push(*reg_num);
             }
+            // The constant of LOADF is always a float, even when written as an integer
+            Token::IntegerOperand { value } if opcode == Opcode::LOADF => {
+                AssemblerInstruction::extract_float(*value as f64, results);
+            }
+            // The integer takes the operand bytes left after the registers
             Token::IntegerOperand { value } => {
-                let converted = *value as i16;
-                let byte1: u8 = converted as u8;
-                let byte2: u8 = (converted >> 8).try_into().unwrap();
-                results.push(byte2);
-                results.push(byte1);
+                match opcode.operand_bytes() - opcode.register_operands() {
+                    0 => return Err(AssemblerError::InvalidOperand { opcode }),
+                    // e.g. the offset of a heap load or store
+                    1 => {
+                        let byte = u8::try_from(*value).map_err(|_| {
+                            AssemblerError::OperandOutOfRange {
+                                opcode,
+                                value: *value,
+                            }
+                        })?;
+                        results.push(byte);
+                    }
+                    // The VM reads the 2 bytes as an unsigned big-endian number
+                    _ => {
+                        let converted = u16::try_from(*value).map_err(|_| {
+                            AssemblerError::OperandOutOfRange {
+                                opcode,
+                                value: *value,
+                            }
+                        })?;
+                        results.extend_from_slice(&converted.to_be_bytes());
+                    }
+                }
+            }
+            Token::FloatOperand { value } if opcode == Opcode::LOADF => {
+                AssemblerInstruction::extract_float(*value, results);
             }
             Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let byte1 = value;
-                    let byte2 = value >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
-                }
+                let value = symbols
+                    .symbol_value(name)
+                    .ok_or_else(|| AssemblerError::UnknownLabel { name: name.clone() })?;
+                let byte1 = value;
+                let byte2 = value >> 8;
+                results.push(byte2 as u8);
+                results.push(byte1 as u8);
             }
 
-            _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
-            }
+            // Strings only belong to directives, and float constants to LOADF
+            _ => return Err(AssemblerError::InvalidOperand { opcode }),
+        }
+        Ok(())
+    }
+
+    // A float takes 8 bytes, after the first word of the instruction
+    fn extract_float(value: f64, results: &mut Vec<u8>) {
+        while results.len() < 4 {
+            results.push(0);
+        }
+        results.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Number of bytes taken by the instruction in the program. Instructions are padded
+    // to 4 bytes, the ones with a float constant take 12 and directives take none
+    pub fn size(&self) -> u32 {
+        match &self.opcode {
+            Some(Token::Op { code }) => 4.max(1 + code.operand_bytes() as u32),
+            Some(_) => 4,
+            None => 0,
         }
     }
 
@@ -81,6 +158,13 @@ impl AssemblerInstruction {
         self.label.is_some()
     }
 
+    pub fn directive_name(&self) -> Option<String> {
+        match &self.directive {
+            Some(Token::Directive { name }) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
     pub fn label_name(&self) -> Option<String> {
         match &self.label {
             Some(l) => {
@@ -95,6 +179,7 @@ impl AssemblerInstruction {
     }
 }
 
+// The trailing whitespace is consumed even without operands, e.g. after `ret`
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
     do_parse!(
         l: opt!(label_declaration) >>
@@ -102,6 +187,7 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
         o1: opt!(operand) >>
         o2: opt!(operand) >>
         o3: opt!(operand) >>
+        opt!(multispace) >>
         (
             AssemblerInstruction{
                 opcode: Some(o),
@@ -130,7 +216,7 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
+    use crate::assembler::symbols::{Symbol, SymbolType};
 
     #[test]
     fn test_parse_instruction_form_one() {
@@ -188,6 +274,136 @@ mod tests {
             ))
         );
     }
+    #[test]
+    fn test_heap_access_to_bytes() {
+        let (_, instruction) = instruction_combined(CompleteStr("lw $1 $2 #4\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::LW as u8, 1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn test_operand_out_of_range() {
+        for (text, value) in [
+            ("lw $1 $2 #300\n", 300),
+            ("sb $1 $2 #-4\n", -4),
+            ("syscall #70000\n", 70000),
+            ("load $0 #-1\n", -1),
+        ] {
+            let (_, instruction) = instruction_combined(CompleteStr(text)).unwrap();
+            let opcode = match instruction.opcode {
+                Some(Token::Op { code }) => code,
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                instruction.to_bytes(&SymbolTable::new()),
+                Err(AssemblerError::OperandOutOfRange { opcode, value })
+            );
+        }
+        let (_, instruction) = instruction_combined(CompleteStr("sb $1 $2 #255\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::SB as u8, 1, 2, 255]
+        );
+        let (_, instruction) = instruction_combined(CompleteStr("load $0 #65535\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::LOAD as u8, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_bitwise_to_bytes() {
+        let (_, instruction) = instruction_combined(CompleteStr("xor $1 $2 $3\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::XOR as u8, 1, 2, 3]
+        );
+        let (_, instruction) = instruction_combined(CompleteStr("not $1 $2\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::NOT as u8, 1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn test_syscall_to_bytes() {
+        let (_, instruction) = instruction_combined(CompleteStr("syscall #300\n")).unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::SYSCALL as u8, 1, 44, 0]
+        );
+    }
+
+    #[test]
+    fn test_float_to_bytes() {
+        let (_, instruction) = instruction_combined(CompleteStr("loadf $f2 #1.5\n")).unwrap();
+        let mut expected = vec![Opcode::LOADF as u8, 2, 0, 0];
+        expected.extend_from_slice(&1.5_f64.to_be_bytes());
+        assert_eq!(instruction.size(), 12);
+        assert_eq!(instruction.to_bytes(&SymbolTable::new()).unwrap(), expected);
+
+        // An integer constant is stored as a float
+        let (_, instruction) = instruction_combined(CompleteStr("loadf $f0 #2\n")).unwrap();
+        let mut expected = vec![Opcode::LOADF as u8, 0, 0, 0];
+        expected.extend_from_slice(&2.0_f64.to_be_bytes());
+        assert_eq!(instruction.size(), 12);
+        assert_eq!(instruction.to_bytes(&SymbolTable::new()).unwrap(), expected);
+
+        let (_, instruction) = instruction_combined(CompleteStr("addf $f0 $f1 $f2\n")).unwrap();
+        assert_eq!(instruction.size(), 4);
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![Opcode::ADDF as u8, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_float_operands() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("x".to_string(), SymbolType::Label, 8));
+        let valid = [
+            "loadf $f0 #1.5\n",
+            "loadf $f0 #2\n",
+            "addf $f1 $f2 $f3\n",
+            "ltf $f1 $f2\n",
+            "itof $1 $f2\n",
+            "ftoi $f1 $2\n",
+            "load $0 #15\n",
+            "jmp $1\n",
+            "call @x\n",
+        ];
+        for text in valid {
+            let (_, instruction) = instruction_combined(CompleteStr(text)).unwrap();
+            let bytes = instruction.to_bytes(&symbols).unwrap();
+            assert_eq!(bytes.len() as u32, instruction.size(), "{}", text);
+        }
+
+        let invalid = [
+            "load $0 #1.5\n",
+            "loadf $f0\n",
+            "loadf $f0 $f1\n",
+            "loadf $f0 @x\n",
+            "loadf $0 #1.5\n",
+            "add $f1 $f2 $f3\n",
+            "addf $1 $2 $3\n",
+            "itof $f1 $2\n",
+            "ftoi $1 $f2\n",
+        ];
+        for text in invalid {
+            let (_, instruction) = instruction_combined(CompleteStr(text)).unwrap();
+            assert!(
+                matches!(
+                    instruction.to_bytes(&symbols),
+                    Err(AssemblerError::InvalidOperand { .. })
+                ),
+                "{}",
+                text
+            );
+        }
+    }
+
     #[test]
     fn test_parse_instruction_form_one_with_label() {
         let result = instruction_combined(CompleteStr("load $0 @test1\n"));
@@ -1,8 +1,11 @@
+use crate::assembler::error::AssemblerError;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::program_parsers::{program, Program};
 use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
 use crate::instruction::Opcode;
 use nom::types::CompleteStr;
 pub mod directive_parsers;
+pub mod error;
 pub mod instruction_parsers;
 pub mod label_parsers;
 pub mod opcode_parsers;
@@ -15,7 +18,10 @@ pub mod symbols;
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
+    FloatRegister { reg_num: u8 },
     IntegerOperand { value: i32 },
+    FloatOperand { value: f64 },
+    StringOperand { value: String },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
@@ -37,6 +43,7 @@ impl Default for AssemblerPhase {
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
+    pub ro_data: Vec<u8>, // read-only data declared by directives, for the VM's data area
 }
 
 impl Assembler {
@@ -46,6 +53,7 @@ impl Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
+            ro_data: vec![],
         }
     }
 
@@ -54,16 +62,25 @@ impl Assembler {
     // symbol table.
     // In the second phase, all instructions are transformed into bytes.
     // The final vector of bytes (program) is returned
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, AssemblerError> {
+        // Each program starts from scratch, so that an assembler can be reused
+        self.phase = AssemblerPhase::First;
+        self.symbols = SymbolTable::new();
+        self.ro_data.clear();
         match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
+            Ok((remainder, program)) => {
+                // Anything left was not understood, and would otherwise be dropped
+                if let Some(line) = remainder.lines().map(str::trim).find(|l| !l.is_empty()) {
+                    return Err(AssemblerError::UnexpectedInput {
+                        line: line.to_string(),
+                    });
+                }
                 self.process_first_phase(&program);
-                Some(self.process_second_phase(&program))
-            }
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                None
+                self.process_second_phase(&program)
             }
+            Err(e) => Err(AssemblerError::ParseError {
+                error: format!("{:?}", e),
+            }),
         }
     }
 
@@ -72,35 +89,52 @@ impl Assembler {
         self.phase = AssemblerPhase::Second;
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(&mut self, p: &Program) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
         for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbols);
+            let mut bytes = i.to_bytes(&self.symbols)?;
             program.append(&mut bytes);
         }
-        program
+        Ok(program)
     }
 
     // Go through every instruction and look for label declarations
     // These are of the form: some_name: <opcode> ...
     // If some one is found, it is added to the symbol vector inside the symbol table
+    // Data directives are stored in the read-only data, and their labels point there
     fn extract_labels(&mut self, p: &Program) {
         let mut c = 0;
         for i in &p.instructions {
+            let (symbol_type, offset) = match i.directive_name().as_deref() {
+                Some("asciiz") => (SymbolType::Data, self.add_string(i)),
+                _ => (SymbolType::Label, c),
+            };
             if i.is_label() {
                 if let Some(name) = i.label_name() {
-                    let symbol = Symbol::new(name, SymbolType::Label, c);
+                    let symbol = Symbol::new(name, symbol_type, offset);
                     self.symbols.add_symbol(symbol);
                 };
             }
-            c += 4;
+            c += i.size();
+        }
+    }
+
+    // Appends the NUL-terminated string of an `.asciiz` directive to the read-only data,
+    // returning its offset
+    fn add_string(&mut self, i: &AssemblerInstruction) -> u32 {
+        let offset = self.ro_data.len() as u32;
+        if let Some(Token::StringOperand { value }) = &i.operand1 {
+            self.ro_data.extend_from_slice(value.as_bytes());
         }
+        self.ro_data.push(0);
+        offset
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::VirtualMachine;
+    use crate::vm::{ExitReason, SharedOutput, VirtualMachine};
+    use std::io;
 
     use super::*;
 
@@ -113,6 +147,160 @@ mod tests {
         let mut vm = VirtualMachine::new();
         assert_eq!(program.len(), 28);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 28);
+        assert_eq!(vm.program().len(), 28);
+    }
+
+    #[test]
+    fn test_assemble_floats() {
+        let mut asm = Assembler::new();
+        let test_string =
+            "loadf $f0 #0.5\nloadf $f1 #2.0\nstart: mulf $f0 $f1 $f2\nftoi $f2 $3\nhlt";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program.len(), 36);
+        // Labels after a float constant account for its 8 bytes
+        assert_eq!(asm.symbols.symbol_value("start"), Some(24));
+        let mut vm = VirtualMachine::new();
+        vm.add_bytes(program);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.float_registers[2], 1.0);
+        assert_eq!(vm.registers[3], 1);
+    }
+
+    #[test]
+    fn test_assemble_integer_float_constant() {
+        let mut asm = Assembler::new();
+        let test_string = "loadf $f0 #2\nnext: ftoi $f0 $1\nhlt";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program.len(), 20);
+        assert_eq!(asm.symbols.symbol_value("next"), Some(12));
+        let mut vm = VirtualMachine::new();
+        vm.set_output(Box::new(SharedOutput::new()));
+        vm.add_bytes(program);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.float_registers[0], 2.0);
+        assert_eq!(vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_assemble_hello_world() {
+        let mut asm = Assembler::new();
+        let test_string = "hello: .asciiz \"Hello, world!\\n\"\nstart: prts @hello\nhlt\n";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(asm.ro_data, b"Hello, world!\n\0");
+        // The directive takes no room in the program
+        assert_eq!(
+            program,
+            vec![Opcode::PRTS as u8, 0, 0, 0, Opcode::HLT as u8, 0, 0, 0]
+        );
+        assert_eq!(asm.symbols.symbol_value("start"), Some(0));
+
+        let mut vm = VirtualMachine::new();
+        let output = SharedOutput::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_ro_data(asm.ro_data.clone());
+        vm.add_bytes(program);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(output.to_string_lossy(), "Hello, world!\n");
+    }
+
+    #[test]
+    fn test_assemble_twice() {
+        let mut asm = Assembler::new();
+        asm.assemble("first: .asciiz \"one\"\nhlt\n").unwrap();
+        asm.assemble("second: .asciiz \"two\"\nprts @second\n")
+            .unwrap();
+        assert_eq!(asm.ro_data, b"two\0");
+        assert_eq!(asm.symbols.symbol_value("first"), None);
+        assert_eq!(asm.symbols.symbol_value("second"), Some(0));
+    }
+
+    #[test]
+    fn test_assemble_string_outside_directive() {
+        let mut asm = Assembler::new();
+        assert_eq!(
+            asm.assemble("prts \"hi\"\nhlt\n"),
+            Err(AssemblerError::UnexpectedInput {
+                line: "\"hi\"".to_string()
+            })
+        );
+        assert_eq!(
+            asm.assemble("load $0 \"x\"\n"),
+            Err(AssemblerError::UnexpectedInput {
+                line: "\"x\"".to_string()
+            })
+        );
+
+        let instruction = AssemblerInstruction {
+            opcode: Some(Token::Op { code: Opcode::PRTS }),
+            label: None,
+            directive: None,
+            operand1: Some(Token::StringOperand {
+                value: "hi".to_string(),
+            }),
+            operand2: None,
+            operand3: None,
+        };
+        assert_eq!(
+            instruction.to_bytes(&asm.symbols),
+            Err(AssemblerError::InvalidOperand {
+                opcode: Opcode::PRTS
+            })
+        );
+    }
+
+    #[test]
+    fn test_assemble_unknown_label() {
+        let mut asm = Assembler::new();
+        for text in ["call @nowhere\n", "prts @nowhere\n", "spawn $1 @nowhere\n"] {
+            assert_eq!(
+                asm.assemble(text),
+                Err(AssemblerError::UnknownLabel {
+                    name: "nowhere".to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_assemble_input() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("readi $1\nreadi $2\nadd $1 $2 $3\nhlt\n")
+            .unwrap();
+        assert_eq!(program[..4], [Opcode::READI as u8, 1, 0, 0]);
+        let mut vm = VirtualMachine::new();
+        vm.set_input(Box::new(io::Cursor::new(b"4\n5\n".to_vec())));
+        vm.set_output(Box::new(SharedOutput::new()));
+        vm.add_bytes(program);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[3], 9);
+    }
+
+    #[test]
+    fn test_assemble_interrupts() {
+        let mut asm = Assembler::new();
+        let test_string = "load $1 #2\nivec $1 @tick\nei\nhlt\ntick: inc $2\niret\n";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program[4..8], [Opcode::IVEC as u8, 1, 0, 16]);
+        assert_eq!(program[20..24], [Opcode::IRET as u8, 0, 0, 0]);
+        let mut vm = VirtualMachine::new();
+        vm.set_output(Box::new(SharedOutput::new()));
+        vm.add_bytes(program);
+        vm.raise_interrupt(2);
+        assert_eq!(vm.verify(), Ok(()));
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[2], 1);
+    }
+
+    #[test]
+    fn test_assemble_call() {
+        let mut asm = Assembler::new();
+        let test_string = "load $1 #5\ncall @double\nhlt\ndouble: add $1 $1 $1\nret\n";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program[4..8], [Opcode::CALL as u8, 0, 12, 0]);
+        let mut vm = VirtualMachine::new();
+        vm.add_bytes(program);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[1], 10);
     }
 }
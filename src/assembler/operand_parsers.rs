@@ -1,17 +1,17 @@
 use crate::assembler::label_parsers::label_usage;
-use crate::assembler::register_parsers::register;
+use crate::assembler::register_parsers::{float_register, register};
 use crate::assembler::Token;
 use nom::digit;
 use nom::types::CompleteStr;
 
 // Parser for integer numbers
-// We preface with `#` in our assembly language: #100
+// We preface with `#` in our assembly language: #100, #-4
 named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             // Look for `#` and pass the result
             tag!("#") >>
-            reg_num: digit >>
+            reg_num: recognize!(tuple!(opt!(tag!("-")), digit)) >>
             (
                 Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
             )
@@ -19,14 +19,81 @@ named!(pub integer_operand<CompleteStr, Token>,
     )
 );
 
+// Parser for floating-point numbers, which must have a decimal point
+// We preface with `#` in our assembly language: #3.14, #-0.5
+named!(pub float_operand<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("#") >>
+            number: recognize!(
+                tuple!(opt!(tag!("-")), digit, tag!("."), digit)
+            ) >>
+            (
+                Token::FloatOperand{value: number.parse::<f64>().unwrap()}
+            )
+        )
+    )
+);
+
+// Parser for strings, used by the data directives: "Hello\n"
+// The escapes \n, \t, \0 and \\ are supported, but not escaped quotes
+named!(pub string_operand<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("\"") >>
+            content: take_until!("\"") >>
+            tag!("\"") >>
+            (
+                Token::StringOperand{value: unescape(&content)}
+            )
+        )
+    )
+);
+
+fn unescape(raw: &str) -> String {
+    let mut value = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('0') => value.push('\0'),
+            // Unknown escapes are kept as they are
+            Some(other) => {
+                value.push('\\');
+                if other != '\\' {
+                    value.push(other);
+                }
+            }
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+// Floats go first, as the integer parser would stop at their decimal point
 named!(pub operand<CompleteStr, Token>,
     alt!(
+        float_operand |
         integer_operand |
         label_usage |
+        float_register |
         register
     )
 );
 
+// Operands of the directives, which can also be strings
+named!(pub directive_operand<CompleteStr, Token>,
+    alt!(
+        string_operand |
+        operand
+    )
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,7 +106,54 @@ mod tests {
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(value, Token::IntegerOperand { value: 10 });
 
+        let result = integer_operand(CompleteStr("#-4"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr(""), Token::IntegerOperand { value: -4 }))
+        );
+
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_string_operand() {
+        let result = string_operand(CompleteStr("\"Hello, world!\\n\""));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::StringOperand {
+                    value: "Hello, world!\n".to_string()
+                }
+            ))
+        );
+        let result = string_operand(CompleteStr("\"a\\\\b\\q\" $1"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr("$1"),
+                Token::StringOperand {
+                    value: "a\\b\\q".to_string()
+                }
+            ))
+        );
+        assert!(string_operand(CompleteStr("\"unterminated")).is_err());
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        let result = float_operand(CompleteStr("#-3.25"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr(""), Token::FloatOperand { value: -3.25 }))
+        );
+        assert!(float_operand(CompleteStr("#3")).is_err());
+
+        let result = operand(CompleteStr("#1.5"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr(""), Token::FloatOperand { value: 1.5 }))
+        );
+    }
 }
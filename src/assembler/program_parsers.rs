@@ -1,4 +1,5 @@
 use crate::assembler::directive_parsers::directive;
+use crate::assembler::error::AssemblerError;
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
 use nom::types::CompleteStr;
 
@@ -10,12 +11,12 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            program.append(&mut instruction.to_bytes(symbols)?);
         }
-        program
+        Ok(program)
     }
 }
 
@@ -44,13 +45,21 @@ mod tests {
         // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
     }
 
+    #[test]
+    fn test_parse_program_bare_opcodes() {
+        let result = program(CompleteStr("push $1\nret\nhlt\n"));
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(3, p.instructions.len());
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100\n"));
         assert_eq!(result.is_ok(), true);
         let (_, program) = result.unwrap();
         let symbols = SymbolTable::new();
-        let bytecode = program.to_bytes(&symbols);
+        let bytecode = program.to_bytes(&symbols).unwrap();
         assert_eq!(bytecode.len(), 4);
     }
 }
@@ -21,6 +21,23 @@ named!(pub register <CompleteStr, Token>,
     )
 );
 
+// Parser for floating-point register index
+// We preface with `$f` in our assembly language:
+// $f3
+named!(pub float_register <CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("$f") >>
+            reg_num: digit >>
+            (
+                Token::FloatRegister{
+                  reg_num: reg_num.parse::<u8>().unwrap()
+                }
+            )
+        )
+    )
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -34,4 +51,15 @@ mod tests {
         let result = register(CompleteStr("$a"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_float_register() {
+        let result = float_register(CompleteStr("$f12"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr(""), Token::FloatRegister { reg_num: 12 }))
+        );
+        let result = float_register(CompleteStr("$12"));
+        assert!(result.is_err());
+    }
 }
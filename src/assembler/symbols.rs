@@ -1,6 +1,7 @@
 #[derive(Debug)]
 pub enum SymbolType {
-    Label,
+    Label, // An offset in the program
+    Data,  // An offset in the read-only data
 }
 
 #[derive(Debug)]
@@ -42,6 +43,16 @@ impl SymbolTable {
         }
         None
     }
+
+    // Closest label declared at or before the program `offset`, with the distance from it
+    pub fn nearest_label(&self, offset: u32) -> Option<(&str, u32)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| matches!(symbol.symbol_type, SymbolType::Label))
+            .filter(|symbol| symbol.offset <= offset)
+            .max_by_key(|symbol| symbol.offset)
+            .map(|symbol| (symbol.name.as_str(), offset - symbol.offset))
+    }
 }
 
 #[cfg(test)]
@@ -61,4 +72,16 @@ mod tests {
         let v = sym.symbol_value("does_not_exist");
         assert_eq!(v.is_some(), false);
     }
+
+    #[test]
+    fn test_nearest_label() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 0));
+        sym.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 12));
+        sym.add_symbol(Symbol::new("text".to_string(), SymbolType::Data, 14));
+        assert_eq!(sym.nearest_label(0), Some(("start", 0)));
+        assert_eq!(sym.nearest_label(8), Some(("start", 8)));
+        assert_eq!(sym.nearest_label(16), Some(("loop", 4)));
+        assert_eq!(SymbolTable::new().nearest_label(4), None);
+    }
 }
@@ -1,15 +1,15 @@
 use nom::types::CompleteStr;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Opcode {
-    HLT,  // Short for halt. Stops the execution.
-    LOAD, // Load a number into a register
-    ADD,  // Add two numbers and save the result in a register
-    SUB,  // Subtract two numbers and save the result in a register
-    MUL,  // Multiply two numbers and save the result in a register
-    DIV,  // Divide two numbers and save the result in a register
-    JMP,  // Short for jump. Absolute jump; move the program counter to a byte in the program
-    JMPF, // Short for jump forwards. Relative jump; move the program counter forwards by x bytes
+    HLT,     // Short for halt. Stops the execution.
+    LOAD,    // Load a number into a register
+    ADD,     // Add two numbers and save the result in a register
+    SUB,     // Subtract two numbers and save the result in a register
+    MUL,     // Multiply two numbers and save the result in a register
+    DIV,     // Divide two numbers and save the result in a register
+    JMP,     // Short for jump. Absolute jump; move the program counter to a byte in the program
+    JMPF,    // Short for jump forwards. Relative jump; move the program counter forwards by x bytes
     JMPB, // Short for jump backwards. Relative jump; move the program counter backwards by x bytes
     EQ,   // Short for equal. Compare if two numbers are equal
     NEQ,  // Short for not equal. Compare if two numbers are not equal
@@ -22,7 +22,56 @@ pub enum Opcode {
     ALOC, // Short for allocate. Extends the size of the heap by the number of bytes in the corresponding register
     INC,  // Short for increment. Increments the value in the register provided by 1
     DEC,  // Short for decrement. Decrements the value in the register provided by 1
-    IGL,  // Short for illegal. Terminates with an error
+    LB,   // Short for load byte. Loads the heap byte at base register + offset into a register
+    LH,   // Short for load halfword. Loads 2 heap bytes at base register + offset into a register
+    LW,   // Short for load word. Loads 4 heap bytes at base register + offset into a register
+    SB,   // Short for store byte. Stores the lowest byte of a register at base register + offset
+    SH,   // Short for store halfword. Stores the lowest 2 bytes of a register at base + offset
+    SW,   // Short for store word. Stores a register in 4 heap bytes at base register + offset
+    MALLOC, // Short for memory allocate. Allocates a heap block and stores its address in a register
+    FREE,   // Releases the heap block whose address is in the register provided
+    REALOC, // Short for reallocate. Moves a heap block to a new block of the given size
+    PUSH,   // Pushes the value of a register onto the stack
+    POP,    // Pops the value on top of the stack into a register
+    CALL,   // Pushes the address of the next instruction onto the stack and jumps to a label
+    RET,    // Short for return. Pops an address from the stack and jumps to it
+    AND,    // Bitwise and of two numbers, saved in a register
+    OR,     // Bitwise or of two numbers, saved in a register
+    XOR,    // Bitwise exclusive or of two numbers, saved in a register
+    NOT,    // Bitwise complement of a number, saved in a register
+    SHL,    // Short for shift left. Shifts a number left by the 5 lowest bits of another
+    SHR,    // Short for shift right. Shifts a number right, filling with zeros
+    SAR,    // Short for shift arithmetic right. Shifts a number right, keeping its sign
+    REM,    // Short for remainder. Moves the remainder of the last DIV into a register
+    MOD,    // Short for modulo. Remainder of dividing two numbers, saved in a register
+    LOADF,  // Short for load float. Loads an f64 constant into a float register
+    ADDF,   // Add two floats and save the result in a float register
+    SUBF,   // Subtract two floats and save the result in a float register
+    MULF,   // Multiply two floats and save the result in a float register
+    DIVF,   // Divide two floats and save the result in a float register
+    EQF,    // Compare if two floats are equal
+    NEQF,   // Compare if two floats are not equal
+    GTF,    // Compare if a float is greater than other
+    LTF,    // Compare if a float is less than other
+    GTEF,   // Compare if a float is greater than or equal to other
+    LTEF,   // Compare if a float is less than or equal to other
+    ITOF,   // Short for integer to float. Converts a register into a float register
+    FTOI,   // Short for float to integer. Converts a float register into a register
+    PRTS,   // Short for print string. Prints the NUL-terminated string at a data label
+    SYSCALL, // Short for system call. Calls the host function registered under a number
+    READI,  // Short for read integer. Reads a line of input as a number into a register
+    READB,  // Short for read byte. Reads a byte of input into the heap
+    READL,  // Short for read line. Reads a line of input into the heap
+    IVEC,   // Short for interrupt vector. Sets the handler of the interrupt in a register
+    EI,     // Short for enable interrupts
+    DI,     // Short for disable interrupts
+    IRET,   // Short for interrupt return. Restores the flags and returns from a handler
+    SPAWN,  // Starts a new process at an address and stores its id in a register
+    SEND,   // Sends a register value to the process whose id is in a register
+    SENDM,  // Short for send memory. Sends heap bytes to the process whose id is in a register
+    RECV,   // Short for receive. Waits for a message and stores its value in a register
+    RECVM,  // Short for receive memory. Waits for a message and stores its bytes in the heap
+    IGL,    // Short for illegal. Terminates with an error
 }
 
 // Create opcode from byte
@@ -49,6 +98,55 @@ impl From<u8> for Opcode {
             17 => Opcode::ALOC,
             18 => Opcode::INC,
             19 => Opcode::DEC,
+            20 => Opcode::LB,
+            21 => Opcode::LH,
+            22 => Opcode::LW,
+            23 => Opcode::SB,
+            24 => Opcode::SH,
+            25 => Opcode::SW,
+            26 => Opcode::MALLOC,
+            27 => Opcode::FREE,
+            28 => Opcode::REALOC,
+            29 => Opcode::PUSH,
+            30 => Opcode::POP,
+            31 => Opcode::CALL,
+            32 => Opcode::RET,
+            33 => Opcode::AND,
+            34 => Opcode::OR,
+            35 => Opcode::XOR,
+            36 => Opcode::NOT,
+            37 => Opcode::SHL,
+            38 => Opcode::SHR,
+            39 => Opcode::SAR,
+            40 => Opcode::REM,
+            41 => Opcode::MOD,
+            42 => Opcode::LOADF,
+            43 => Opcode::ADDF,
+            44 => Opcode::SUBF,
+            45 => Opcode::MULF,
+            46 => Opcode::DIVF,
+            47 => Opcode::EQF,
+            48 => Opcode::NEQF,
+            49 => Opcode::GTF,
+            50 => Opcode::LTF,
+            51 => Opcode::GTEF,
+            52 => Opcode::LTEF,
+            53 => Opcode::ITOF,
+            54 => Opcode::FTOI,
+            55 => Opcode::PRTS,
+            56 => Opcode::SYSCALL,
+            57 => Opcode::READI,
+            58 => Opcode::READB,
+            59 => Opcode::READL,
+            60 => Opcode::IVEC,
+            61 => Opcode::EI,
+            62 => Opcode::DI,
+            63 => Opcode::IRET,
+            64 => Opcode::SPAWN,
+            65 => Opcode::SEND,
+            66 => Opcode::SENDM,
+            67 => Opcode::RECV,
+            68 => Opcode::RECVM,
             _ => Opcode::IGL,
         }
     }
@@ -78,11 +176,217 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("aloc") => Opcode::ALOC,
             CompleteStr("inc") => Opcode::INC,
             CompleteStr("dec") => Opcode::DEC,
+            CompleteStr("lb") => Opcode::LB,
+            CompleteStr("lh") => Opcode::LH,
+            CompleteStr("lw") => Opcode::LW,
+            CompleteStr("sb") => Opcode::SB,
+            CompleteStr("sh") => Opcode::SH,
+            CompleteStr("sw") => Opcode::SW,
+            CompleteStr("malloc") => Opcode::MALLOC,
+            CompleteStr("free") => Opcode::FREE,
+            CompleteStr("realoc") => Opcode::REALOC,
+            CompleteStr("push") => Opcode::PUSH,
+            CompleteStr("pop") => Opcode::POP,
+            CompleteStr("call") => Opcode::CALL,
+            CompleteStr("ret") => Opcode::RET,
+            CompleteStr("and") => Opcode::AND,
+            CompleteStr("or") => Opcode::OR,
+            CompleteStr("xor") => Opcode::XOR,
+            CompleteStr("not") => Opcode::NOT,
+            CompleteStr("shl") => Opcode::SHL,
+            CompleteStr("shr") => Opcode::SHR,
+            CompleteStr("sar") => Opcode::SAR,
+            CompleteStr("rem") => Opcode::REM,
+            CompleteStr("mod") => Opcode::MOD,
+            CompleteStr("loadf") => Opcode::LOADF,
+            CompleteStr("addf") => Opcode::ADDF,
+            CompleteStr("subf") => Opcode::SUBF,
+            CompleteStr("mulf") => Opcode::MULF,
+            CompleteStr("divf") => Opcode::DIVF,
+            CompleteStr("eqf") => Opcode::EQF,
+            CompleteStr("neqf") => Opcode::NEQF,
+            CompleteStr("gtf") => Opcode::GTF,
+            CompleteStr("ltf") => Opcode::LTF,
+            CompleteStr("gtef") => Opcode::GTEF,
+            CompleteStr("ltef") => Opcode::LTEF,
+            CompleteStr("itof") => Opcode::ITOF,
+            CompleteStr("ftoi") => Opcode::FTOI,
+            CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("readi") => Opcode::READI,
+            CompleteStr("readb") => Opcode::READB,
+            CompleteStr("readl") => Opcode::READL,
+            CompleteStr("ivec") => Opcode::IVEC,
+            CompleteStr("ei") => Opcode::EI,
+            CompleteStr("di") => Opcode::DI,
+            CompleteStr("iret") => Opcode::IRET,
+            CompleteStr("spawn") => Opcode::SPAWN,
+            CompleteStr("send") => Opcode::SEND,
+            CompleteStr("sendm") => Opcode::SENDM,
+            CompleteStr("recv") => Opcode::RECV,
+            CompleteStr("recvm") => Opcode::RECVM,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl Opcode {
+    // Number of bytes that follow the opcode byte in the program
+    pub fn operand_bytes(&self) -> usize {
+        match self {
+            Opcode::HLT | Opcode::IGL => 0,
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::ALOC => 1,
+            Opcode::LOAD
+            | Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTE
+            | Opcode::LTE
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::LB
+            | Opcode::LH
+            | Opcode::LW
+            | Opcode::SB
+            | Opcode::SH
+            | Opcode::SW
+            | Opcode::MALLOC
+            | Opcode::FREE
+            | Opcode::REALOC
+            | Opcode::PUSH
+            | Opcode::POP
+            | Opcode::CALL
+            | Opcode::RET
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::NOT
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::REM
+            | Opcode::MOD
+            | Opcode::ADDF
+            | Opcode::SUBF
+            | Opcode::MULF
+            | Opcode::DIVF
+            | Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEF
+            | Opcode::LTEF
+            | Opcode::ITOF
+            | Opcode::FTOI
+            | Opcode::PRTS
+            | Opcode::SYSCALL
+            | Opcode::READI
+            | Opcode::READB
+            | Opcode::READL
+            | Opcode::IVEC
+            | Opcode::EI
+            | Opcode::DI
+            | Opcode::IRET
+            | Opcode::SPAWN
+            | Opcode::SEND
+            | Opcode::SENDM
+            | Opcode::RECV
+            | Opcode::RECVM => 3,
+            // The float register, 2 bytes of padding and the f64 constant
+            Opcode::LOADF => 11,
+        }
+    }
+
+    // Number of operand bytes, right after the opcode, that are register indices.
+    // The operand bytes after them are an immediate value or padding
+    pub fn register_operands(&self) -> usize {
+        match self {
+            Opcode::HLT | Opcode::IGL => 0,
+            // CALL and PRTS have a 2-byte address instead, and SYSCALL a 2-byte number
+            Opcode::CALL | Opcode::RET | Opcode::PRTS | Opcode::SYSCALL => 0,
+            Opcode::EI | Opcode::DI | Opcode::IRET => 0,
+            Opcode::LOAD
+            | Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::FREE
+            | Opcode::PUSH
+            | Opcode::POP
+            | Opcode::REM
+            | Opcode::LOADF
+            | Opcode::READI
+            | Opcode::READB => 1,
+            // The interrupt register, then the 2-byte handler address
+            Opcode::IVEC => 1,
+            // The process id register, then the 2-byte start address
+            Opcode::SPAWN => 1,
+            Opcode::RECV => 1,
+            // The process id register and the value register
+            Opcode::SEND => 2,
+            // The process id register, the address register and the length register
+            Opcode::SENDM => 3,
+            // The address register, the capacity register and the length register
+            Opcode::RECVM => 3,
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => 2,
+            // The value register, the base register and a 1-byte offset
+            Opcode::LB | Opcode::LH | Opcode::LW | Opcode::SB | Opcode::SH | Opcode::SW => 2,
+            // The size register and the destination register
+            Opcode::MALLOC => 2,
+            // The source register and the destination register
+            Opcode::NOT | Opcode::ITOF | Opcode::FTOI => 2,
+            Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEF
+            | Opcode::LTEF => 2,
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::MOD => 3,
+            // The block register, the size register and the destination register
+            Opcode::REALOC => 3,
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => 3,
+            // The address register, the capacity register and the length register
+            Opcode::READL => 3,
+            Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::SAR => 3,
+        }
+    }
+
+    // Whether the register operand at `index` is a float register
+    pub fn is_float_register(&self, index: usize) -> bool {
+        match self {
+            // The integer register comes first, then the float register
+            Opcode::ITOF => index == 1,
+            Opcode::FTOI => index == 0,
+            Opcode::LOADF
+            | Opcode::ADDF
+            | Opcode::SUBF
+            | Opcode::MULF
+            | Opcode::DIVF
+            | Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEF
+            | Opcode::LTEF => index < self.register_operands(),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -118,7 +422,46 @@ mod tests {
         assert_eq!(opcode, Opcode::JMP);
         let opcode = Opcode::from(CompleteStr("gte"));
         assert_eq!(opcode, Opcode::GTE);
+        let opcode = Opcode::from(CompleteStr("lw"));
+        assert_eq!(opcode, Opcode::LW);
+        let opcode = Opcode::from(CompleteStr("sb"));
+        assert_eq!(opcode, Opcode::SB);
+        let opcode = Opcode::from(CompleteStr("readl"));
+        assert_eq!(opcode, Opcode::READL);
+        let opcode = Opcode::from(CompleteStr("iret"));
+        assert_eq!(opcode, Opcode::IRET);
+        let opcode = Opcode::from(CompleteStr("spawn"));
+        assert_eq!(opcode, Opcode::SPAWN);
+        let opcode = Opcode::from(CompleteStr("recvm"));
+        assert_eq!(opcode, Opcode::RECVM);
+        let opcode = Opcode::from(CompleteStr("realoc"));
+        assert_eq!(opcode, Opcode::REALOC);
+        let opcode = Opcode::from(CompleteStr("call"));
+        assert_eq!(opcode, Opcode::CALL);
+        let opcode = Opcode::from(CompleteStr("sar"));
+        assert_eq!(opcode, Opcode::SAR);
+        let opcode = Opcode::from(CompleteStr("mod"));
+        assert_eq!(opcode, Opcode::MOD);
+        let opcode = Opcode::from(CompleteStr("ftoi"));
+        assert_eq!(opcode, Opcode::FTOI);
         let opcode = Opcode::from(CompleteStr("caca"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_operand_bytes() {
+        assert_eq!(Opcode::HLT.operand_bytes(), 0);
+        assert_eq!(Opcode::JMP.operand_bytes(), 1);
+        assert_eq!(Opcode::LOAD.operand_bytes(), 3);
+        assert_eq!(Opcode::LOADF.operand_bytes(), 11);
+        assert_eq!(Opcode::IGL.operand_bytes(), 0);
+    }
+
+    #[test]
+    fn test_register_operands() {
+        assert_eq!(Opcode::LOAD.register_operands(), 1);
+        assert_eq!(Opcode::EQ.register_operands(), 2);
+        assert_eq!(Opcode::ADD.register_operands(), 3);
+        assert_eq!(Opcode::HLT.register_operands(), 0);
+    }
 }
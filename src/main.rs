@@ -1,3 +1,9 @@
+// The assembler keeps its own constructors and the boolean assertions of its tests
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::derivable_impls,
+    clippy::new_without_default
+)]
 pub mod assembler;
 pub mod instruction;
 pub mod repl;
@@ -31,8 +31,16 @@ impl Repl {
         // Print a welcome message with available commands
         println!("Welcome to flavia VM!");
         println!(
-            "Type {:?}, {:?}, {:?}, {:?}, {:?} for more information",
-            ".prog", ".reg", ".history", ".load_file", ".clear_program"
+            "Type {:?}, {:?}, {:?}, {:?}, {:?}, {:?} for more information",
+            ".prog", ".reg", ".freg", ".history", ".load_file", ".clear_program"
+        );
+        println!(
+            "Type {:?} or {:?} to checkpoint the VM",
+            ".save_state <file>", ".load_state <file>"
+        );
+        println!(
+            "Type {:?} to start profiling, and again for the report",
+            ".profile"
         );
         println!("Type {:?} to exit", ".q");
 
@@ -74,13 +82,19 @@ impl Repl {
             match buffer {
                 ".prog" => {
                     println!("List of instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
+                    for instruction in self.vm.program() {
                         println!("{}", instruction);
                     }
                 }
                 ".reg" => {
                     println!("List of the registers' contents:");
                     println!("{:#?}", self.vm.registers);
+                    println!("Remainder: {}", self.vm.remainder());
+                    println!("End of list.");
+                }
+                ".freg" => {
+                    println!("List of the float registers' contents:");
+                    println!("{:#?}", self.vm.float_registers);
                     println!("End of list.");
                 }
                 ".q" => {
@@ -88,7 +102,7 @@ impl Repl {
                     std::process::exit(0);
                 }
                 ".clear_program" => {
-                    self.vm.program.clear();
+                    self.vm.load_program(vec![]);
                 }
                 ".load_file" => {
                     print!("Please enter the path to the file you wish to load: ");
@@ -104,18 +118,48 @@ impl Repl {
                     f.read_to_string(&mut contents)
                         .expect("There was an error reading from the file");
                     match self.asm.assemble(&contents) {
-                        Some(mut assembled_program) => {
+                        Ok(assembled_program) => {
                             println!("Sending assembled program to VM");
-                            self.vm.program.append(&mut assembled_program);
-                            println!("{:#?}", self.vm.program);
-                            self.vm.run();
+                            self.vm.set_ro_data(self.asm.ro_data.clone());
+                            self.vm.add_bytes(assembled_program);
+                            println!("{:#?}", self.vm.program());
+                            if let Err(e) = self.vm.run() {
+                                println!("Execution failed: {}", e);
+                            }
                         }
-                        None => {
-                            println!("Unable to parse input");
+                        Err(e) => {
+                            println!("Unable to assemble the file: {}", e);
                             continue;
                         }
                     }
                 }
+                ".profile" => match self.vm.disable_profiling() {
+                    Some(profiler) => {
+                        println!("Profiling disabled. Profile of the execution:");
+                        print!("{}", profiler.report(Some(&self.asm.symbols)));
+                    }
+                    None => {
+                        self.vm.enable_profiling();
+                        println!(
+                            "Profiling enabled. Type {:?} again for the report",
+                            ".profile"
+                        );
+                    }
+                },
+                cmd if cmd.starts_with(".save_state") => match cmd.split_whitespace().nth(1) {
+                    Some(path) => match self.vm.save_state(path) {
+                        Ok(()) => println!("VM state saved to {}", path),
+                        Err(e) => println!("Unable to save the VM state: {}", e),
+                    },
+                    None => println!("Usage: .save_state <file>"),
+                },
+                cmd if cmd.starts_with(".load_state") => match cmd.split_whitespace().nth(1) {
+                    Some(path) => match self.vm.load_state(path) {
+                        Ok(()) => println!("VM state loaded from {}", path),
+                        Err(e) => println!("Unable to load the VM state: {}", e),
+                    },
+                    None => println!("Usage: .load_state <file>"),
+                },
                 ".history" => {
                     for cmd in &self.command_buffer {
                         println!("{}", cmd);
@@ -124,17 +168,27 @@ impl Repl {
                 _ => {
                     let program = match program(buffer.into()) {
                         // Rusts pattern matching is pretty powerful an can even be nested
-                        Ok((_remainder, program)) => program,
+                        Ok((remainder, program)) if remainder.trim().is_empty() => program,
+                        Ok((remainder, _)) => {
+                            println!("Unable to parse input: {}", remainder);
+                            continue;
+                        }
                         Err(e) => {
                             println!("Unable to parse input: {:?}", e);
                             continue;
                         }
                     };
 
-                    self.vm
-                        .program
-                        .append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+                    match program.to_bytes(&self.asm.symbols) {
+                        Ok(bytes) => self.vm.add_bytes(bytes),
+                        Err(e) => {
+                            println!("Unable to assemble input: {}", e);
+                            continue;
+                        }
+                    }
+                    if let Err(e) = self.vm.run_once() {
+                        println!("Execution failed: {}", e);
+                    }
                 }
             }
         }
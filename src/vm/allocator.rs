@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+// Keeps track of the heap blocks handed out by MALLOC and REALOC. The blocks live in the
+// heap next to the bytes added with ALOC, and freed blocks are reused by later allocations
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Allocator {
+    allocated: BTreeMap<usize, usize>, // base address -> size of the blocks in use
+    free: BTreeMap<usize, usize>,      // base address -> size of the freed blocks
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator::default()
+    }
+
+    // Size of the block in use that starts at `address`
+    pub fn block_size(&self, address: usize) -> Option<usize> {
+        self.allocated.get(&address).copied()
+    }
+
+    // Blocks in use, as (base address, size), by address
+    pub fn allocated(&self) -> impl ExactSizeIterator<Item = (usize, usize)> + '_ {
+        self.allocated.iter().map(|(&base, &size)| (base, size))
+    }
+
+    // Freed blocks, as (base address, size), by address
+    pub fn freed(&self) -> impl ExactSizeIterator<Item = (usize, usize)> + '_ {
+        self.free.iter().map(|(&base, &size)| (base, size))
+    }
+
+    // Whether any of the `len` bytes at `address` belongs to a freed block
+    pub fn is_freed(&self, address: usize, len: usize) -> bool {
+        // Freed blocks do not overlap, so only the last one starting before the end of
+        // the range can reach into it
+        match self.free.range(..address + len.max(1)).next_back() {
+            Some((&base, &size)) => base + size > address,
+            None => false,
+        }
+    }
+
+    // Finds room for `size` bytes, reusing the first freed block that is large enough or
+    // else placing the block at the end of the heap. Returns the base address, or `None`
+    // when the heap would have to grow beyond `max_len`. The caller grows the heap when
+    // the block ends past it
+    pub(crate) fn allocate(
+        &mut self,
+        size: usize,
+        heap_len: usize,
+        max_len: usize,
+    ) -> Option<usize> {
+        let reused = self
+            .free
+            .iter()
+            .find(|(_, &free_size)| free_size >= size)
+            .map(|(&base, &free_size)| (base, free_size));
+        let base = match reused {
+            Some((base, free_size)) => {
+                self.free.remove(&base);
+                if free_size > size {
+                    self.free.insert(base + size, free_size - size);
+                }
+                base
+            }
+            None if heap_len + size <= max_len => heap_len,
+            None => return None,
+        };
+        self.allocated.insert(base, size);
+        Some(base)
+    }
+
+    // Releases the block that starts at `address`, returning its size. Returns `None` when
+    // no block in use starts there
+    pub(crate) fn free(&mut self, address: usize) -> Option<usize> {
+        let size = self.allocated.remove(&address)?;
+        let mut base = address;
+        let mut len = size;
+        // Merge with the neighbouring freed blocks
+        if let Some(next) = self.free.remove(&(address + size)) {
+            len += next;
+        }
+        if let Some((&prev, &prev_size)) = self.free.range(..address).next_back() {
+            if prev + prev_size == address {
+                self.free.remove(&prev);
+                base = prev;
+                len += prev_size;
+            }
+        }
+        self.free.insert(base, len);
+        Some(size)
+    }
+
+    // Restores blocks read from a snapshot
+    pub(crate) fn insert_allocated(&mut self, base: usize, size: usize) {
+        self.allocated.insert(base, size);
+    }
+
+    pub(crate) fn insert_freed(&mut self, base: usize, size: usize) {
+        self.free.insert(base, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_free() {
+        let mut allocator = Allocator::new();
+        assert_eq!(allocator.allocate(8, 4, 64), Some(4));
+        assert_eq!(allocator.allocate(4, 12, 64), Some(12));
+        assert_eq!(allocator.block_size(4), Some(8));
+        assert_eq!(allocator.allocate(64, 16, 64), None);
+
+        assert_eq!(allocator.free(4), Some(8));
+        assert_eq!(allocator.free(4), None);
+        assert!(allocator.is_freed(10, 4));
+        assert!(!allocator.is_freed(12, 4));
+        assert!(!allocator.is_freed(0, 4));
+
+        // The freed block is reused and split
+        assert_eq!(allocator.allocate(2, 16, 64), Some(4));
+        assert_eq!(allocator.freed().collect::<Vec<_>>(), vec![(6, 6)]);
+    }
+
+    #[test]
+    fn test_free_merges_blocks() {
+        let mut allocator = Allocator::new();
+        for (size, heap_len) in [(4, 0), (4, 4), (4, 8)] {
+            allocator.allocate(size, heap_len, 64);
+        }
+        allocator.free(0);
+        allocator.free(8);
+        allocator.free(4);
+        assert_eq!(allocator.freed().collect::<Vec<_>>(), vec![(0, 12)]);
+        assert_eq!(allocator.allocated().count(), 0);
+        assert_eq!(allocator.allocate(12, 12, 12), Some(0));
+    }
+}
@@ -0,0 +1,89 @@
+// How the integer arithmetic instructions behave when the result does not fit in an i32.
+// The mode is set on the VM, so a program behaves the same in debug and release builds
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum ArithmeticMode {
+    #[default]
+    Wrapping, // The result wraps around (two's complement)
+    Saturating, // The result is clamped to i32::MIN or i32::MAX
+    Trapping,   // The program stops with an overflow error
+}
+
+// Every operation returns `None` when the result overflowed and the mode is `Trapping`
+impl ArithmeticMode {
+    pub fn add(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            ArithmeticMode::Wrapping => Some(a.wrapping_add(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_add(b)),
+            ArithmeticMode::Trapping => a.checked_add(b),
+        }
+    }
+
+    pub fn sub(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            ArithmeticMode::Wrapping => Some(a.wrapping_sub(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_sub(b)),
+            ArithmeticMode::Trapping => a.checked_sub(b),
+        }
+    }
+
+    pub fn mul(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            ArithmeticMode::Wrapping => Some(a.wrapping_mul(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_mul(b)),
+            ArithmeticMode::Trapping => a.checked_mul(b),
+        }
+    }
+
+    // The divisor must not be 0. The only overflowing division is i32::MIN / -1
+    pub fn div(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            ArithmeticMode::Wrapping => Some(a.wrapping_div(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_div(b)),
+            ArithmeticMode::Trapping => a.checked_div(b),
+        }
+    }
+
+    // The divisor must not be 0. i32::MIN % -1 is 0 except when trapping
+    pub fn rem(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            ArithmeticMode::Wrapping | ArithmeticMode::Saturating => Some(a.wrapping_rem(b)),
+            ArithmeticMode::Trapping => a.checked_rem(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapping() {
+        let mode = ArithmeticMode::Wrapping;
+        assert_eq!(mode.add(i32::MAX, 1), Some(i32::MIN));
+        assert_eq!(mode.sub(i32::MIN, 1), Some(i32::MAX));
+        assert_eq!(mode.mul(i32::MAX, 2), Some(-2));
+        assert_eq!(mode.div(i32::MIN, -1), Some(i32::MIN));
+        assert_eq!(mode.rem(i32::MIN, -1), Some(0));
+    }
+
+    #[test]
+    fn test_saturating() {
+        let mode = ArithmeticMode::Saturating;
+        assert_eq!(mode.add(i32::MAX, 1), Some(i32::MAX));
+        assert_eq!(mode.sub(i32::MIN, 1), Some(i32::MIN));
+        assert_eq!(mode.mul(i32::MIN, 2), Some(i32::MIN));
+        assert_eq!(mode.div(i32::MIN, -1), Some(i32::MAX));
+        assert_eq!(mode.rem(i32::MIN, -1), Some(0));
+    }
+
+    #[test]
+    fn test_trapping() {
+        let mode = ArithmeticMode::Trapping;
+        assert_eq!(mode.add(i32::MAX, 1), None);
+        assert_eq!(mode.sub(i32::MIN, 1), None);
+        assert_eq!(mode.mul(i32::MAX, 2), None);
+        assert_eq!(mode.div(i32::MIN, -1), None);
+        assert_eq!(mode.rem(i32::MIN, -1), None);
+        assert_eq!(mode.add(2, 3), Some(5));
+    }
+}
@@ -0,0 +1,43 @@
+use crate::vm::VirtualMachine;
+
+// A piece of the VM state that stops the execution when an instruction changes it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    Register(usize),                   // The register with the given index
+    Heap { start: usize, end: usize }, // The heap bytes in `start..end`
+    EqualFlag,                         // The result of the last comparison
+    Remainder,                         // The remainder of the last division
+}
+
+// Why a run stopped before the program finished
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StoppedAt {
+    // Stopped before executing the instruction at this offset
+    Breakpoint(usize),
+    // The instruction at `pc` changed the watched value
+    Watch { pc: usize, watch: Watch },
+}
+
+// Value of a watch, taken before and after each instruction to detect changes
+#[derive(Debug, PartialEq)]
+pub(crate) enum WatchValue {
+    Int(i32),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+impl Watch {
+    pub(crate) fn read(&self, vm: &VirtualMachine) -> WatchValue {
+        match *self {
+            Watch::Register(idx) => WatchValue::Int(vm.registers.get(idx).copied().unwrap_or(0)),
+            Watch::Heap { start, end } => {
+                // Only the part of the range that is currently allocated is compared
+                let end = end.min(vm.heap.len());
+                let start = start.min(end);
+                WatchValue::Bytes(vm.heap[start..end].to_vec())
+            }
+            Watch::EqualFlag => WatchValue::Bool(vm.equal_flag),
+            Watch::Remainder => WatchValue::Int(vm.remainder),
+        }
+    }
+}
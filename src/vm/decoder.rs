@@ -0,0 +1,146 @@
+use crate::instruction::Opcode;
+use crate::vm::VmError;
+
+// An instruction read from the program, with its operands already extracted and its
+// register indices checked
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    pub registers: [usize; 3], // register operands, in order. Unused ones are 0
+    pub immediate: u16,        // big-endian value of the 2 operand bytes after the registers
+    pub float: f64,            // the f64 constant of LOADF, 0 for the other instructions
+    pub len: usize,            // bytes taken in the program, opcode included
+}
+
+// Instructions indexed by their offset in the program
+pub type DecodedProgram = Vec<Option<DecodedInstruction>>;
+
+// Decodes the instruction starting at `pc`, which must be within the program
+pub fn decode(program: &[u8], pc: usize) -> Result<DecodedInstruction, VmError> {
+    let opcode = Opcode::from(program[pc]);
+    let len = 1 + opcode.operand_bytes();
+    let operands = program
+        .get(pc + 1..pc + len)
+        .ok_or(VmError::TruncatedInstruction { pc })?;
+
+    let (register_bytes, immediate_bytes) = operands.split_at(opcode.register_operands());
+    let mut registers = [0; 3];
+    for (register, &index) in registers.iter_mut().zip(register_bytes) {
+        if index >= 32 {
+            return Err(VmError::InvalidRegister { pc, index });
+        }
+        *register = index as usize;
+    }
+    // Any byte after the first 2 is padding, except the 8 bytes of a float constant
+    let immediate = immediate_bytes
+        .iter()
+        .take(2)
+        .fold(0, |value, &byte| (value << 8) | byte as u16);
+    let float = match immediate_bytes.get(2..10) {
+        Some(bytes) => f64::from_be_bytes(bytes.try_into().unwrap()),
+        None => 0.0,
+    };
+
+    Ok(DecodedInstruction {
+        opcode,
+        registers,
+        immediate,
+        float,
+        len,
+    })
+}
+
+// Decodes the program once, following the instructions from offset 0. The result is
+// indexed by offset: offsets that do not start an instruction of that sequence, or
+// whose instruction cannot be decoded, are `None` and must be decoded when reached
+pub fn predecode(program: &[u8]) -> DecodedProgram {
+    let mut decoded = vec![None; program.len()];
+    let mut pc = 0;
+    while pc < program.len() {
+        match decode(program, pc) {
+            Ok(instruction) => {
+                decoded[pc] = Some(instruction);
+                pc += instruction.len;
+            }
+            Err(_) => pc += 1 + Opcode::from(program[pc]).operand_bytes(),
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let program = vec![Opcode::LOAD as u8, 4, 1, 244, Opcode::ADD as u8, 1, 2, 3];
+        assert_eq!(
+            decode(&program, 0),
+            Ok(DecodedInstruction {
+                opcode: Opcode::LOAD,
+                registers: [4, 0, 0],
+                immediate: 500,
+                float: 0.0,
+                len: 4,
+            })
+        );
+        assert_eq!(
+            decode(&program, 4),
+            Ok(DecodedInstruction {
+                opcode: Opcode::ADD,
+                registers: [1, 2, 3],
+                immediate: 0,
+                float: 0.0,
+                len: 4,
+            })
+        );
+        // The byte after the address of a CALL is padding
+        let call = decode(&[Opcode::CALL as u8, 1, 4, 9], 0).unwrap();
+        assert_eq!(call.immediate, 260);
+
+        let mut loadf = vec![Opcode::LOADF as u8, 3, 0, 0];
+        loadf.extend_from_slice(&(-0.5_f64).to_be_bytes());
+        let loadf = decode(&loadf, 0).unwrap();
+        assert_eq!((loadf.registers[0], loadf.float, loadf.len), (3, -0.5, 12));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let program = vec![Opcode::ADD as u8, 1, 40, 3, Opcode::LOAD as u8, 1];
+        assert_eq!(
+            decode(&program, 0),
+            Err(VmError::InvalidRegister { pc: 0, index: 40 })
+        );
+        assert_eq!(
+            decode(&program, 4),
+            Err(VmError::TruncatedInstruction { pc: 4 })
+        );
+    }
+
+    #[test]
+    fn test_predecode() {
+        let program = vec![
+            Opcode::JMP as u8,
+            1,
+            Opcode::ADD as u8,
+            1,
+            40,
+            3,
+            Opcode::INC as u8,
+            2,
+            0,
+            0,
+            Opcode::LOAD as u8,
+            1,
+        ];
+        let decoded = predecode(&program);
+        assert_eq!(decoded.len(), program.len());
+        assert_eq!(decoded[0].map(|i| i.opcode), Some(Opcode::JMP));
+        assert_eq!(decoded[1], None);
+        assert_eq!(decoded[2], None);
+        assert_eq!(decoded[6].map(|i| i.opcode), Some(Opcode::INC));
+        assert_eq!(decoded[7], None);
+        assert_eq!(decoded[10], None);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+// A peripheral mapped into the address space of the VM. LB, LH, LW, SB, SH and SW reach
+// it one byte at a time, at an offset from the start of its range. Returning an error stops
+// the program with `VmError::DeviceFailed`
+pub trait Device: Send {
+    // Number of bytes the device takes in the address space
+    fn size(&self) -> usize;
+    fn read(&mut self, offset: usize) -> Result<u8, String>;
+    fn write(&mut self, offset: usize, value: u8) -> Result<(), String>;
+    // Called after every instruction the VM executes
+    fn tick(&mut self) {}
+}
+
+// Why a device could not be mapped
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapError {
+    // The range starts below the end of the heap
+    OverlapsHeap { base: usize, heap_len: usize },
+    // The range overlaps the device mapped at `other`
+    OverlapsDevice { base: usize, other: usize },
+    // The range cannot be reached with an address held in a register
+    Unaddressable { base: usize, size: usize },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::OverlapsHeap { base, heap_len } => {
+                write!(
+                    f,
+                    "device at {} overlaps the heap of {} bytes",
+                    base, heap_len
+                )
+            }
+            MapError::OverlapsDevice { base, other } => {
+                write!(f, "device at {} overlaps the device at {}", base, other)
+            }
+            MapError::Unaddressable { base, size } => {
+                write!(f, "device of {} bytes at {} is out of reach", size, base)
+            }
+        }
+    }
+}
+
+// Routes addresses to the devices mapped on them. Addresses without a device belong to
+// the heap
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<(usize, Box<dyn Device>)>, // base address and device, by address
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    // Base address of the lowest device
+    pub fn lowest_base(&self) -> Option<usize> {
+        self.devices.first().map(|&(base, _)| base)
+    }
+
+    // Maps the device on the bytes from `base`, which must not be used by another device
+    // or by the heap up to `heap_len`
+    pub fn map(
+        &mut self,
+        base: usize,
+        device: Box<dyn Device>,
+        heap_len: usize,
+    ) -> Result<(), MapError> {
+        let size = device.size();
+        if base
+            .checked_add(size)
+            .is_none_or(|end| end > i32::MAX as usize + 1)
+        {
+            return Err(MapError::Unaddressable { base, size });
+        }
+        if base < heap_len {
+            return Err(MapError::OverlapsHeap { base, heap_len });
+        }
+        if let Some(&(other, _)) = self
+            .devices
+            .iter()
+            .find(|(other, d)| base < other + d.size() && *other < base + size)
+        {
+            return Err(MapError::OverlapsDevice { base, other });
+        }
+        let index = self.devices.partition_point(|&(other, _)| other < base);
+        self.devices.insert(index, (base, device));
+        Ok(())
+    }
+
+    // Removes the device mapped at `base`
+    pub fn unmap(&mut self, base: usize) -> Option<Box<dyn Device>> {
+        let index = self.devices.iter().position(|&(other, _)| other == base)?;
+        Some(self.devices.remove(index).1)
+    }
+
+    // The device whose range holds `address`, with the offset of the address in it and
+    // the number of bytes left in the range
+    pub(crate) fn find(&mut self, address: usize) -> Option<(&mut dyn Device, usize, usize)> {
+        let index = self.devices.partition_point(|&(base, _)| base <= address);
+        let (base, device) = self.devices.get_mut(index.checked_sub(1)?)?;
+        let offset = address - *base;
+        let size = device.size();
+        if offset < size {
+            Some((device.as_mut(), offset, size - offset))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn tick(&mut self) {
+        for (_, device) in self.devices.iter_mut() {
+            device.tick();
+        }
+    }
+}
+
+// Prints the bytes stored at any of its addresses. Reading it gives 0
+pub struct Console {
+    output: Box<dyn Write + Send>,
+}
+
+impl Console {
+    pub fn new(output: Box<dyn Write + Send>) -> Console {
+        Console { output }
+    }
+}
+
+impl Device for Console {
+    fn size(&self) -> usize {
+        1
+    }
+
+    fn read(&mut self, _offset: usize) -> Result<u8, String> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _offset: usize, value: u8) -> Result<(), String> {
+        self.output
+            .write_all(&[value])
+            .and_then(|_| self.output.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// A grid of one-byte pixels, row by row. Clones share the same pixels, so that one can
+// be mapped on the VM and the other kept to look at the picture
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: Arc::new(Mutex::new(vec![0; width * height])),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> Vec<u8> {
+        self.pixels.lock().unwrap().clone()
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u8> {
+        if x < self.width && y < self.height {
+            Some(self.pixels.lock().unwrap()[y * self.width + x])
+        } else {
+            None
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        self.width * self.height
+    }
+
+    fn read(&mut self, offset: usize) -> Result<u8, String> {
+        Ok(self.pixels.lock().unwrap()[offset])
+    }
+
+    fn write(&mut self, offset: usize, value: u8) -> Result<(), String> {
+        self.pixels.lock().unwrap()[offset] = value;
+        Ok(())
+    }
+}
+
+// Counts the instructions executed since it was mapped, as a 4-byte big-endian number
+// like the ones of LW and SW. Storing into it sets the count
+#[derive(Clone, Debug, Default)]
+pub struct Timer {
+    count: u32,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, offset: usize) -> Result<u8, String> {
+        Ok(self.count.to_be_bytes()[offset])
+    }
+
+    fn write(&mut self, offset: usize, value: u8) -> Result<(), String> {
+        let mut bytes = self.count.to_be_bytes();
+        bytes[offset] = value;
+        self.count = u32::from_be_bytes(bytes);
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bus_mapping() {
+        let mut bus = Bus::new();
+        assert_eq!(bus.map(8, Box::new(Timer::new()), 0), Ok(()));
+        assert_eq!(bus.map(0, Box::new(Framebuffer::new(2, 2)), 0), Ok(()));
+        assert_eq!(
+            bus.map(4, Box::new(Timer::new()), 6),
+            Err(MapError::OverlapsHeap {
+                base: 4,
+                heap_len: 6
+            })
+        );
+        assert_eq!(
+            bus.map(10, Box::new(Timer::new()), 0),
+            Err(MapError::OverlapsDevice { base: 10, other: 8 })
+        );
+        assert_eq!(
+            bus.map(i32::MAX as usize, Box::new(Timer::new()), 0),
+            Err(MapError::Unaddressable {
+                base: i32::MAX as usize,
+                size: 4
+            })
+        );
+        assert_eq!(bus.lowest_base(), Some(0));
+
+        assert_eq!(
+            bus.find(9).map(|(_, offset, left)| (offset, left)),
+            Some((1, 3))
+        );
+        assert!(bus.find(4).is_none());
+        assert!(bus.find(12).is_none());
+        assert!(bus.unmap(0).is_some());
+        assert_eq!(bus.lowest_base(), Some(8));
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new();
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.read(3), Ok(2));
+        timer.write(2, 1).unwrap();
+        assert_eq!(timer.count(), 0x102);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::vm::debug::StoppedAt;
+use crate::vm::verifier::VerifyError;
+use std::error::Error;
+use std::fmt;
+
+// Why the VM stopped executing instructions without an error
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExitReason {
+    Halted,             // A HLT instruction was executed
+    EndOfProgram,       // The program counter reached the end of the program
+    Stepped,            // Only returned by `run_once`: the program can go on
+    Stopped(StoppedAt), // A breakpoint or watchpoint was hit; running again resumes
+    Blocked,            // RECV or RECVM found no message; running again waits again
+}
+
+// Outcome of running the VM with an instruction budget
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunStatus {
+    Exited(ExitReason), // The program stopped before the budget ran out
+    BudgetExhausted,    // The budget ran out; running again resumes at the next instruction
+    Error(VmError),     // The program failed
+}
+
+// Errors raised while executing a program, so a faulty program can be reported instead of
+// crashing the host. Most variants carry in `pc` the offset of the instruction that caused it
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    // The divisor of a DIV was 0
+    DivideByZero {
+        pc: usize,
+    },
+    // A register operand was not below 32
+    InvalidRegister {
+        pc: usize,
+        index: u8,
+    },
+    // The program ends in the middle of the instruction
+    TruncatedInstruction {
+        pc: usize,
+    },
+    // The program counter left the program. `pc` is the offset it was moved to, or the
+    // offset of the jump when the target was negative
+    PcOutOfBounds {
+        pc: usize,
+    },
+    // The byte at `pc` is not a known opcode
+    IllegalOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    // Overflow while in ArithmeticMode::Trapping
+    ArithmeticOverflow {
+        pc: usize,
+    },
+    // The program was rejected before running
+    VerificationFailed(Vec<VerifyError>),
+    // A load or store outside of the heap
+    HeapOutOfBounds {
+        pc: usize,
+        address: i64,
+    },
+    // ALOC, MALLOC or REALOC was given a negative size, or a zero size to allocate
+    InvalidAllocationSize {
+        pc: usize,
+        size: i32,
+    },
+    // The heap would grow beyond the maximum heap size
+    HeapLimitExceeded {
+        pc: usize,
+        requested: usize,
+    },
+    // FREE or REALOC was given an address that is not the start of an allocated block
+    InvalidFree {
+        pc: usize,
+        address: i32,
+    },
+    // FREE or REALOC was given a block that was already freed
+    DoubleFree {
+        pc: usize,
+        address: usize,
+    },
+    // A load or store touched a freed block
+    UseAfterFree {
+        pc: usize,
+        address: usize,
+    },
+    // PUSH or CALL on a full stack
+    StackOverflow {
+        pc: usize,
+    },
+    // POP or RET on an empty stack
+    StackUnderflow {
+        pc: usize,
+    },
+    // PRTS was given an offset with no NUL-terminated string in the read-only data
+    InvalidString {
+        pc: usize,
+        address: usize,
+    },
+    // No host function is registered under the SYSCALL number
+    UnknownSyscall {
+        pc: usize,
+        number: u16,
+    },
+    // The host function returned an error
+    SyscallFailed {
+        pc: usize,
+        number: u16,
+        message: String,
+    },
+    // Writing to the output sink failed
+    OutputFailed {
+        pc: usize,
+        message: String,
+    },
+    // IVEC was given a number that is not below INTERRUPT_VECTORS
+    InvalidInterrupt {
+        pc: usize,
+        number: i32,
+    },
+    // SPAWN, SEND or SENDM was executed by a VM that is not run by a scheduler
+    NoScheduler {
+        pc: usize,
+    },
+    // SEND or SENDM was given an id that no process was started with
+    UnknownProcess {
+        pc: usize,
+        pid: i32,
+    },
+    // RECV took a message that is not a 4-byte value. It is left in the mailbox
+    InvalidMessage {
+        pc: usize,
+        len: usize,
+    },
+    // A device mapped in the address space refused an access
+    DeviceFailed {
+        pc: usize,
+        address: usize,
+        message: String,
+    },
+    // Reading the input failed, or READI did not read a number
+    InputFailed {
+        pc: usize,
+        message: String,
+    },
+    // READL, SENDM or RECVM was given a negative length or capacity
+    InvalidLength {
+        pc: usize,
+        len: i32,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::DivideByZero { pc } => write!(f, "division by zero at {}", pc),
+            VmError::InvalidRegister { pc, index } => {
+                write!(f, "invalid register ${} at {}", index, pc)
+            }
+            VmError::TruncatedInstruction { pc } => write!(f, "truncated instruction at {}", pc),
+            VmError::PcOutOfBounds { pc } => write!(f, "program counter out of bounds: {}", pc),
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode {} at {}", byte, pc)
+            }
+            VmError::ArithmeticOverflow { pc } => write!(f, "arithmetic overflow at {}", pc),
+            VmError::HeapOutOfBounds { pc, address } => {
+                write!(
+                    f,
+                    "heap access out of bounds at {}: address {}",
+                    pc, address
+                )
+            }
+            VmError::InvalidAllocationSize { pc, size } => {
+                write!(f, "invalid allocation size {} at {}", size, pc)
+            }
+            VmError::HeapLimitExceeded { pc, requested } => {
+                write!(
+                    f,
+                    "heap limit exceeded at {}: {} bytes requested",
+                    pc, requested
+                )
+            }
+            VmError::InvalidFree { pc, address } => {
+                write!(f, "address {} is not an allocated block at {}", address, pc)
+            }
+            VmError::DoubleFree { pc, address } => {
+                write!(f, "double free of block {} at {}", address, pc)
+            }
+            VmError::UseAfterFree { pc, address } => {
+                write!(f, "use after free of address {} at {}", address, pc)
+            }
+            VmError::StackOverflow { pc } => write!(f, "stack overflow at {}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "stack underflow at {}", pc),
+            VmError::InvalidString { pc, address } => {
+                write!(f, "no string at data offset {} at {}", address, pc)
+            }
+            VmError::UnknownSyscall { pc, number } => {
+                write!(f, "unknown syscall {} at {}", number, pc)
+            }
+            VmError::SyscallFailed {
+                pc,
+                number,
+                message,
+            } => write!(f, "syscall {} failed at {}: {}", number, pc, message),
+            VmError::OutputFailed { pc, message } => {
+                write!(f, "output failed at {}: {}", pc, message)
+            }
+            VmError::InvalidInterrupt { pc, number } => {
+                write!(f, "invalid interrupt {} at {}", number, pc)
+            }
+            VmError::NoScheduler { pc } => {
+                write!(f, "no scheduler to reach other processes at {}", pc)
+            }
+            VmError::UnknownProcess { pc, pid } => {
+                write!(f, "unknown process {} at {}", pid, pc)
+            }
+            VmError::InvalidMessage { pc, len } => {
+                write!(f, "message of {} bytes is not a value at {}", len, pc)
+            }
+            VmError::DeviceFailed {
+                pc,
+                address,
+                message,
+            } => write!(f, "device at {} failed at {}: {}", address, pc, message),
+            VmError::InputFailed { pc, message } => {
+                write!(f, "input failed at {}: {}", pc, message)
+            }
+            VmError::InvalidLength { pc, len } => write!(f, "invalid length {} at {}", len, pc),
+            VmError::VerificationFailed(errors) => {
+                write!(f, "program failed verification:")?;
+                for e in errors {
+                    write!(f, " {};", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for VmError {}
@@ -0,0 +1,13 @@
+use std::io::{self, Read};
+
+// Default input of the VM. Every read locks the process stdin and goes through its
+// buffer, so the VM takes only the bytes it uses and leaves the rest of the line for the
+// REPL, which reads from the same stdin
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdinInput;
+
+impl Read for StdinInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().lock().read(buf)
+    }
+}
@@ -0,0 +1,137 @@
+// Number of entries in the interrupt vector table
+pub const INTERRUPT_VECTORS: usize = 16;
+// Interrupt raised by the periodic timer
+pub const TIMER_INTERRUPT: usize = 0;
+
+// Bits of the flags word saved on the stack when a handler is entered
+pub(crate) const FLAG_EQUAL: i32 = 1;
+pub(crate) const FLAG_INTERRUPTS_ENABLED: i32 = 2;
+
+// State of the interrupt controller. Raised interrupts stay pending until interrupts are
+// enabled and a handler is set for them, and the lowest pending number is handled first
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Interrupts {
+    // Offset of the handler of each interrupt, set by IVEC
+    pub(crate) vectors: [Option<usize>; INTERRUPT_VECTORS],
+    // One bit per raised interrupt that was not handled yet
+    pub(crate) pending: u16,
+    // Whether pending interrupts are handled, set by EI and DI
+    pub(crate) enabled: bool,
+    // Instructions between two timer interrupts, 0 when the timer is off
+    pub(crate) timer_period: u32,
+    // Instructions left until the next timer interrupt
+    pub(crate) timer_countdown: u32,
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts::default()
+    }
+
+    // Offset of the handler set for the interrupt
+    pub fn vector(&self, number: usize) -> Option<usize> {
+        self.vectors.get(number).copied().flatten()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_pending(&self, number: usize) -> bool {
+        number < INTERRUPT_VECTORS && self.pending & (1 << number) != 0
+    }
+
+    pub fn timer_period(&self) -> u32 {
+        self.timer_period
+    }
+
+    // Marks the interrupt as pending. Returns false when there is no such interrupt
+    pub(crate) fn raise(&mut self, number: usize) -> bool {
+        if number >= INTERRUPT_VECTORS {
+            return false;
+        }
+        self.pending |= 1 << number;
+        true
+    }
+
+    pub(crate) fn set_timer_period(&mut self, period: u32) {
+        self.timer_period = period;
+        self.timer_countdown = period;
+    }
+
+    // Counts an executed instruction, raising the timer interrupt at the end of a period
+    pub(crate) fn tick(&mut self) {
+        if self.timer_period == 0 {
+            return;
+        }
+        self.timer_countdown = self.timer_countdown.saturating_sub(1);
+        if self.timer_countdown == 0 {
+            self.raise(TIMER_INTERRUPT);
+            self.timer_countdown = self.timer_period;
+        }
+    }
+
+    // Whether an interrupt is ready to be handled
+    pub(crate) fn is_deliverable(&self) -> bool {
+        self.enabled && self.handled_pending() != 0
+    }
+
+    // Takes the lowest pending interrupt that has a handler, returning the handler offset
+    pub(crate) fn take(&mut self) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+        let pending = self.handled_pending();
+        if pending == 0 {
+            return None;
+        }
+        let number = pending.trailing_zeros() as usize;
+        self.pending &= !(1 << number);
+        self.vectors[number]
+    }
+
+    fn handled_pending(&self) -> u16 {
+        let handled = (0..INTERRUPT_VECTORS)
+            .filter(|&number| self.vectors[number].is_some())
+            .fold(0, |mask, number| mask | 1 << number);
+        self.pending & handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut interrupts = Interrupts::new();
+        interrupts.vectors[3] = Some(40);
+        interrupts.vectors[5] = Some(80);
+        assert!(interrupts.raise(5));
+        assert!(interrupts.raise(3));
+        assert!(interrupts.raise(1));
+        assert!(!interrupts.raise(INTERRUPT_VECTORS));
+        // Nothing is handled while interrupts are disabled
+        assert!(!interrupts.is_deliverable());
+        assert_eq!(interrupts.take(), None);
+
+        interrupts.enabled = true;
+        assert_eq!(interrupts.take(), Some(40));
+        assert_eq!(interrupts.take(), Some(80));
+        // The interrupt without a handler stays pending
+        assert_eq!(interrupts.take(), None);
+        assert!(interrupts.is_pending(1));
+    }
+
+    #[test]
+    fn test_timer_period() {
+        let mut interrupts = Interrupts::new();
+        interrupts.set_timer_period(3);
+        interrupts.tick();
+        interrupts.tick();
+        assert!(!interrupts.is_pending(TIMER_INTERRUPT));
+        interrupts.tick();
+        assert!(interrupts.is_pending(TIMER_INTERRUPT));
+        assert_eq!(interrupts.timer_countdown, 3);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::vm::allocator::Allocator;
+use crate::vm::{Interrupts, Message, VmState};
+use std::collections::VecDeque;
+
+// What an executed instruction changed, holding the previous values so it can be undone
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JournalEntry {
+    pub pc: usize,                          // offset of the instruction
+    pub registers: Vec<(usize, i32)>,       // registers it wrote, with their previous value
+    pub float_registers: Vec<(usize, f64)>, // float registers it wrote, likewise
+    pub remainder: i32,                     // remainder before the instruction
+    pub equal_flag: bool,                   // equal flag before the instruction
+    pub heap_len: usize,                    // heap length before the instruction
+    pub heap: Vec<(usize, u8)>,             // heap bytes it overwrote, with their previous value
+    pub allocator: Option<Allocator>,       // allocator before the instruction, if it changed it
+    pub stack_len: usize,                   // stack length before the instruction
+    pub stack: Vec<i32>,                    // values it popped from the stack, in order
+    pub interrupts: Option<Interrupts>,     // interrupt state before the instruction, if changed
+    pub message: Option<Message>,           // message it took from the mailbox
+}
+
+// Bounded history of the last executed instructions. When full, the oldest entry is dropped
+#[derive(Debug)]
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+    registers: [i32; 32], // registers before the instruction being recorded
+    float_registers: [f64; 32], // float registers before the instruction being recorded
+    interrupts: Interrupts, // interrupt state before the instruction being recorded
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Journal {
+        Journal {
+            entries: VecDeque::new(),
+            capacity,
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            interrupts: Interrupts::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn last(&self) -> Option<&JournalEntry> {
+        self.entries.back()
+    }
+
+    pub fn contains_pc(&self, pc: usize) -> bool {
+        self.entries.iter().any(|entry| entry.pc == pc)
+    }
+
+    // Starts recording the instruction at `state.pc`, given the state before it runs
+    pub(crate) fn begin(&mut self, state: &VmState) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.registers = *state.registers;
+        self.float_registers = *state.float_registers;
+        self.interrupts = *state.interrupts;
+        self.entries.push_back(JournalEntry {
+            pc: state.pc,
+            remainder: state.remainder,
+            equal_flag: state.equal_flag,
+            heap_len: state.heap.len(),
+            stack_len: state.stack.len(),
+            ..JournalEntry::default()
+        });
+    }
+
+    // Records the previous value of a heap byte the current instruction overwrites
+    pub(crate) fn record_heap(&mut self, address: usize, previous: u8) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.heap.push((address, previous));
+        }
+    }
+
+    // Records a value the current instruction popped from the stack
+    pub(crate) fn record_pop(&mut self, value: i32) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.stack.push(value);
+        }
+    }
+
+    // Records the message the current instruction took from the mailbox
+    pub(crate) fn record_message(&mut self, message: &Message) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.message = Some(message.clone());
+        }
+    }
+
+    // Records the allocator before the current instruction changes it. Only the first
+    // call of an instruction is kept
+    pub(crate) fn record_allocator(&mut self, allocator: &Allocator) {
+        if let Some(entry) = self.entries.back_mut() {
+            if entry.allocator.is_none() {
+                entry.allocator = Some(allocator.clone());
+            }
+        }
+    }
+
+    // Finishes the current entry, given the registers and the interrupt state after the
+    // instruction ran
+    pub(crate) fn commit(
+        &mut self,
+        registers: &[i32; 32],
+        float_registers: &[f64; 32],
+        interrupts: &Interrupts,
+    ) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.interrupts = (*interrupts != self.interrupts).then_some(self.interrupts);
+            entry.registers = (0..registers.len())
+                .filter(|&i| registers[i] != self.registers[i])
+                .map(|i| (i, self.registers[i]))
+                .collect();
+            // Compared bit by bit, so that NaNs are not reported as changes
+            entry.float_registers = (0..float_registers.len())
+                .filter(|&i| float_registers[i].to_bits() != self.float_registers[i].to_bits())
+                .map(|i| (i, self.float_registers[i]))
+                .collect();
+        }
+        // The oldest entry is only dropped once the new one is kept
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    // Drops the current entry, for an instruction that did not run
+    pub(crate) fn discard(&mut self) {
+        self.entries.pop_back();
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<JournalEntry> {
+        self.entries.pop_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state<'a>(
+        pc: usize,
+        registers: &'a [i32; 32],
+        float_registers: &'a [f64; 32],
+        interrupts: &'a Interrupts,
+    ) -> VmState<'a> {
+        VmState {
+            registers,
+            float_registers,
+            heap: &[0; 10],
+            stack: &[1, 2],
+            pc,
+            remainder: 1,
+            equal_flag: true,
+            interrupts,
+        }
+    }
+
+    #[test]
+    fn test_journal_records_changed_registers() {
+        let mut journal = Journal::new(8);
+        let mut registers = [0; 32];
+        let mut float_registers = [0.0; 32];
+        let mut interrupts = Interrupts::new();
+        registers[3] = 9;
+        journal.begin(&state(4, &registers, &float_registers, &interrupts));
+        registers[3] = 10;
+        registers[5] = -1;
+        float_registers[1] = 0.5;
+        journal.record_heap(2, 7);
+        journal.record_pop(8);
+        interrupts.enabled = true;
+        journal.commit(&registers, &float_registers, &interrupts);
+        assert_eq!(
+            journal.last(),
+            Some(&JournalEntry {
+                pc: 4,
+                registers: vec![(3, 9), (5, 0)],
+                float_registers: vec![(1, 0.0)],
+                remainder: 1,
+                equal_flag: true,
+                heap_len: 10,
+                heap: vec![(2, 7)],
+                allocator: None,
+                stack_len: 2,
+                stack: vec![8],
+                interrupts: Some(Interrupts::new()),
+                message: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_journal_capacity() {
+        let mut journal = Journal::new(2);
+        let registers = [0; 32];
+        let float_registers = [0.0; 32];
+        let interrupts = Interrupts::new();
+        for pc in [0, 4, 8] {
+            journal.begin(&state(pc, &registers, &float_registers, &interrupts));
+            journal.commit(&registers, &float_registers, &interrupts);
+        }
+        assert_eq!(journal.len(), 2);
+        assert!(!journal.contains_pc(0));
+        assert_eq!(journal.pop().map(|e| e.pc), Some(8));
+    }
+
+    #[test]
+    fn test_journal_discard() {
+        let mut journal = Journal::new(2);
+        let registers = [0; 32];
+        let float_registers = [0.0; 32];
+        let interrupts = Interrupts::new();
+        for pc in [0, 4] {
+            journal.begin(&state(pc, &registers, &float_registers, &interrupts));
+            journal.commit(&registers, &float_registers, &interrupts);
+        }
+        journal.begin(&state(8, &registers, &float_registers, &interrupts));
+        journal.discard();
+        assert_eq!(journal.len(), 2);
+        assert!(journal.contains_pc(0));
+        assert!(!journal.contains_pc(8));
+    }
+}
@@ -0,0 +1,3268 @@
+use crate::instruction::Opcode;
+use crate::vm::decoder::{decode, predecode};
+use crate::vm::interrupt::{FLAG_EQUAL, FLAG_INTERRUPTS_ENABLED};
+use crate::vm::scheduler::ProcessContext;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+
+pub mod allocator;
+pub mod arithmetic;
+pub mod debug;
+pub mod decoder;
+pub mod device;
+pub mod error;
+pub mod input;
+pub mod interrupt;
+pub mod journal;
+pub mod observer;
+pub mod output;
+pub mod profiler;
+pub mod runtime;
+pub mod scheduler;
+pub mod snapshot;
+pub mod syscall;
+pub mod verifier;
+
+pub use self::allocator::Allocator;
+pub use self::arithmetic::ArithmeticMode;
+pub use self::debug::{StoppedAt, Watch};
+pub use self::decoder::{DecodedInstruction, DecodedProgram};
+pub use self::device::{Bus, Console, Device, Framebuffer, MapError, Timer};
+pub use self::error::{ExitReason, RunStatus, VmError};
+pub use self::input::StdinInput;
+pub use self::interrupt::{Interrupts, INTERRUPT_VECTORS, TIMER_INTERRUPT};
+pub use self::journal::{Journal, JournalEntry};
+pub use self::observer::{VmObserver, VmState};
+pub use self::output::SharedOutput;
+pub use self::profiler::{ProfileReport, Profiler};
+pub use self::runtime::{Outcome, Runtime, VmHandle};
+pub use self::scheduler::{Message, Policy, Process, ProcessStatus, Scheduler, SpawnHook};
+pub use self::snapshot::SnapshotError;
+pub use self::syscall::{Syscall, SyscallContext, SyscallTable};
+pub use self::verifier::{verify, VerifyError};
+
+// Default limit of the heap size, in bytes
+pub const DEFAULT_MAX_HEAP_SIZE: usize = 16 * 1024 * 1024;
+// Default limit of the number of values on the stack
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1024;
+
+pub struct VirtualMachine {
+    pub registers: [i32; 32],              // register set
+    pub float_registers: [f64; 32],        // floating-point register set
+    heap: Vec<u8>,                         // heap memory
+    max_heap_size: usize,                  // size the heap cannot grow beyond
+    allocator: Allocator,                  // blocks allocated in the heap by MALLOC
+    stack: Vec<i32>,                       // values pushed by PUSH and return addresses of CALL
+    max_stack_size: usize,                 // number of values the stack cannot grow beyond
+    pc: usize,                             // program counter
+    program: Vec<u8>,                      // vector to store the bytecode
+    ro_data: Vec<u8>,                      // read-only data, such as the strings of PRTS
+    remainder: i32,                        // to store the remainder of a division
+    equal_flag: bool,                      // to store the result of the last comparison operation
+    arithmetic: ArithmeticMode,            // behaviour of the arithmetic instructions on overflow
+    breakpoints: HashSet<usize>,           // offsets where a run stops before executing them
+    watchpoints: Vec<Watch>,               // values where a run stops after they change
+    resume_pc: Option<usize>,              // breakpoint skipped when resuming a run
+    observer: Option<Box<dyn VmObserver>>, // hooks called around every instruction
+    syscalls: SyscallTable,                // host functions called by SYSCALL
+    output: Box<dyn Write + Send>,         // where the program prints, stdout by default
+    input: Box<dyn Read + Send>,           // what the program reads, stdin by default
+    bus: Bus,                              // devices mapped in the address space
+    interrupts: Interrupts,                // interrupt vectors, pending interrupts and timer
+    process: Option<ProcessContext>,       // what SPAWN and SEND reach, under a scheduler
+    mailbox: VecDeque<Message>,            // messages received and not taken by RECV yet
+    profiler: Option<Profiler>,            // execution counts, when profiling is enabled
+    journal: Option<Journal>,              // undo history, when journaling is enabled
+    decoded: DecodedProgram,               // the program decoded by `predecode`
+    require_verification: bool,            // whether to verify the program before running it
+    verified: bool,                        // whether `decoded` passed the verifier
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        VirtualMachine::new()
+    }
+}
+
+impl VirtualMachine {
+    pub fn new() -> VirtualMachine {
+        VirtualMachine {
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            heap: vec![],
+            max_heap_size: DEFAULT_MAX_HEAP_SIZE,
+            allocator: Allocator::new(),
+            stack: vec![],
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            pc: 0,
+            program: vec![],
+            ro_data: vec![],
+            remainder: 0,
+            equal_flag: false,
+            arithmetic: ArithmeticMode::default(),
+            breakpoints: HashSet::new(),
+            watchpoints: vec![],
+            resume_pc: None,
+            observer: None,
+            syscalls: HashMap::new(),
+            output: Box::new(io::stdout()),
+            input: Box::new(StdinInput),
+            bus: Bus::new(),
+            interrupts: Interrupts::new(),
+            process: None,
+            mailbox: VecDeque::new(),
+            profiler: None,
+            journal: None,
+            decoded: vec![],
+            require_verification: false,
+            verified: false,
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    // Replaces the read-only data, usually with the one built by the assembler
+    pub fn set_ro_data(&mut self, ro_data: Vec<u8>) {
+        self.ro_data = ro_data;
+    }
+
+    pub fn max_heap_size(&self) -> usize {
+        self.max_heap_size
+    }
+
+    // Limits the heap growth of ALOC, MALLOC and REALOC. A heap that is already larger
+    // is kept, but cannot grow any further
+    pub fn set_max_heap_size(&mut self, max_heap_size: usize) {
+        self.max_heap_size = max_heap_size;
+    }
+
+    pub fn allocator(&self) -> &Allocator {
+        &self.allocator
+    }
+
+    // The stack, bottom first
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn max_stack_size(&self) -> usize {
+        self.max_stack_size
+    }
+
+    // Limits the number of values on the stack. A stack that is already larger is kept,
+    // but nothing more can be pushed onto it
+    pub fn set_max_stack_size(&mut self, max_stack_size: usize) {
+        self.max_stack_size = max_stack_size;
+    }
+
+    // Remainder of the last DIV. It has the sign of the dividend, so that
+    // dividend == quotient * divisor + remainder
+    pub fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic = mode;
+    }
+
+    // A run stops before executing the instruction at `offset`
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // A run stops after any instruction that changes the watched value. Returns false
+    // when the watched register does not exist
+    pub fn add_watchpoint(&mut self, watch: Watch) -> bool {
+        if let Watch::Register(index) = watch {
+            if index >= self.registers.len() {
+                return false;
+            }
+        }
+        if !self.watchpoints.contains(&watch) {
+            self.watchpoints.push(watch);
+        }
+        true
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: Watch) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watch);
+        self.watchpoints.len() != len
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // Attaches an observer, returning the one previously attached
+    pub fn set_observer(&mut self, observer: Box<dyn VmObserver>) -> Option<Box<dyn VmObserver>> {
+        self.observer.replace(observer)
+    }
+
+    pub fn take_observer(&mut self) -> Option<Box<dyn VmObserver>> {
+        self.observer.take()
+    }
+
+    // Redirects everything the program prints, returning the previous sink.
+    // `SharedOutput` captures it in memory
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.output, output)
+    }
+
+    // Replaces what READI, READB and READL read, returning the previous source. The VM
+    // reads a byte at a time, so files are best wrapped in a `BufReader`. In-memory input
+    // can be an `io::Cursor`
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        std::mem::replace(&mut self.input, input)
+    }
+
+    // Maps a device on the addresses from `base`, so that the heap load and store
+    // instructions reach it instead of the heap. The heap cannot grow into a device, so
+    // devices are best placed above the maximum heap size. Device accesses are not undone
+    // by `step_back`, and devices are not saved in snapshots
+    pub fn map_device(&mut self, base: usize, device: Box<dyn Device>) -> Result<(), MapError> {
+        self.bus.map(base, device, self.heap.len())
+    }
+
+    // Removes the device mapped at `base`
+    pub fn unmap_device(&mut self, base: usize) -> Option<Box<dyn Device>> {
+        self.bus.unmap(base)
+    }
+
+    pub fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    // Raises an interrupt from the host. It is handled before the next instruction once
+    // interrupts are enabled and its handler is set. Returns false when the number is not
+    // below `INTERRUPT_VECTORS`
+    pub fn raise_interrupt(&mut self, number: usize) -> bool {
+        self.interrupts.raise(number)
+    }
+
+    // Raises `TIMER_INTERRUPT` every `period` executed instructions. A period of 0 turns
+    // the timer off
+    pub fn set_timer_interrupt(&mut self, period: u32) {
+        self.interrupts.set_timer_period(period);
+    }
+
+    // Messages received and not taken by RECV or RECVM yet, oldest first
+    pub fn mailbox(&self) -> &VecDeque<Message> {
+        &self.mailbox
+    }
+
+    // Puts a message in the mailbox. `Scheduler::post_message` also wakes up a process
+    // waiting for it
+    pub fn post_message(&mut self, message: Message) {
+        self.mailbox.push_back(message);
+    }
+
+    // Makes `syscall #number` call the host function, returning the one previously
+    // registered under that number
+    pub fn register_syscall(
+        &mut self,
+        number: u16,
+        syscall: Box<dyn Syscall>,
+    ) -> Option<Box<dyn Syscall>> {
+        self.syscalls.insert(number, syscall)
+    }
+
+    pub fn unregister_syscall(&mut self, number: u16) -> Option<Box<dyn Syscall>> {
+        self.syscalls.remove(&number)
+    }
+
+    // Starts counting executed instructions, keeping the counts gathered so far
+    pub fn enable_profiling(&mut self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(Profiler::new());
+        }
+    }
+
+    // Stops profiling and returns the gathered counts
+    pub fn disable_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Starts recording the changes made by each instruction, keeping the last `capacity`
+    // ones, so that they can be undone with `step_back` and `run_back_to`
+    pub fn enable_journal(&mut self, capacity: usize) {
+        self.journal = Some(Journal::new(capacity));
+    }
+
+    pub fn disable_journal(&mut self) -> Option<Journal> {
+        self.journal.take()
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    // Undoes the last executed instruction. Returns false when there is nothing to undo
+    pub fn step_back(&mut self) -> bool {
+        let entry = match self.journal.as_mut().and_then(|journal| journal.pop()) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        for (idx, value) in entry.registers {
+            self.registers[idx] = value;
+        }
+        for (idx, value) in entry.float_registers {
+            self.float_registers[idx] = value;
+        }
+        self.remainder = entry.remainder;
+        self.equal_flag = entry.equal_flag;
+        // The recorded bytes may lie past either length of the heap
+        self.heap.resize(self.heap.len().max(entry.heap_len), 0);
+        for (address, byte) in entry.heap.into_iter().rev() {
+            self.heap[address] = byte;
+        }
+        self.heap.truncate(entry.heap_len);
+        // The values were popped before anything was pushed
+        self.stack
+            .truncate(entry.stack_len.saturating_sub(entry.stack.len()));
+        self.stack.extend(entry.stack.into_iter().rev());
+        if let Some(allocator) = entry.allocator {
+            self.allocator = allocator;
+        }
+        if let Some(interrupts) = entry.interrupts {
+            self.interrupts = interrupts;
+        }
+        // Messages sent to other processes cannot be taken back
+        if let Some(message) = entry.message {
+            self.mailbox.push_front(message);
+        }
+        self.pc = entry.pc;
+        // Running again must not stop right away on a breakpoint at this offset
+        self.resume_pc = Some(self.pc);
+        true
+    }
+
+    // Undoes instructions until the VM is back before the last execution of the
+    // instruction at `pc`. Returns false, without undoing anything, when the journal
+    // does not go back that far
+    pub fn run_back_to(&mut self, pc: usize) -> bool {
+        match &self.journal {
+            Some(journal) if journal.contains_pc(pc) => {}
+            _ => return false,
+        }
+        while self.step_back() {
+            if self.pc == pc {
+                break;
+            }
+        }
+        true
+    }
+
+    // Resizes the heap, journaling the bytes that are dropped
+    fn resize_heap(&mut self, new_len: usize) {
+        if let Some(journal) = self.journal.as_mut() {
+            for address in new_len..self.heap.len() {
+                journal.record_heap(address, self.heap[address]);
+            }
+        }
+        self.heap.resize(new_len, 0);
+    }
+
+    // Address of a heap access of `size` bytes at the base register plus the offset,
+    // checked against the heap
+    fn heap_address(
+        &self,
+        pc: usize,
+        base: usize,
+        offset: u16,
+        size: usize,
+    ) -> Result<usize, VmError> {
+        let address = self.registers[base] as i64 + offset as i64;
+        match usize::try_from(address) {
+            Ok(a) if a + size <= self.heap.len() => {
+                if self.allocator.is_freed(a, size) {
+                    return Err(VmError::UseAfterFree { pc, address: a });
+                }
+                Ok(a)
+            }
+            _ => Err(VmError::HeapOutOfBounds { pc, address }),
+        }
+    }
+
+    // The device an access of `size` bytes at the base register plus the offset falls on,
+    // with the offset in the device and the address. The access must fit in the device
+    fn device_at(
+        &mut self,
+        pc: usize,
+        base: usize,
+        offset: u16,
+        size: usize,
+    ) -> Result<Option<(&mut dyn Device, usize, usize)>, VmError> {
+        if self.bus.is_empty() {
+            return Ok(None);
+        }
+        let address = self.registers[base] as i64 + offset as i64;
+        let Ok(a) = usize::try_from(address) else {
+            return Ok(None);
+        };
+        match self.bus.find(a) {
+            Some((device, first, left)) if size <= left => Ok(Some((device, first, a))),
+            Some(_) => Err(VmError::HeapOutOfBounds { pc, address }),
+            None => Ok(None),
+        }
+    }
+
+    // Reads `N` bytes at the base register plus the offset, from the device mapped there
+    // or else from the heap
+    fn load<const N: usize>(
+        &mut self,
+        pc: usize,
+        base: usize,
+        offset: u16,
+    ) -> Result<[u8; N], VmError> {
+        let mut bytes = [0; N];
+        if let Some((device, first, address)) = self.device_at(pc, base, offset, N)? {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = device
+                    .read(first + i)
+                    .map_err(|message| VmError::DeviceFailed {
+                        pc,
+                        address: address + i,
+                        message,
+                    })?;
+            }
+        } else {
+            let address = self.heap_address(pc, base, offset, N)?;
+            bytes.copy_from_slice(&self.heap[address..address + N]);
+        }
+        Ok(bytes)
+    }
+
+    // Writes the bytes at the base register plus the offset, to the device mapped there or
+    // else to the heap
+    fn store(&mut self, pc: usize, base: usize, offset: u16, bytes: &[u8]) -> Result<(), VmError> {
+        if let Some((device, first, address)) = self.device_at(pc, base, offset, bytes.len())? {
+            for (i, &byte) in bytes.iter().enumerate() {
+                device
+                    .write(first + i, byte)
+                    .map_err(|message| VmError::DeviceFailed {
+                        pc,
+                        address: address + i,
+                        message,
+                    })?;
+            }
+        } else {
+            let address = self.heap_address(pc, base, offset, bytes.len())?;
+            self.write_heap(address, bytes);
+        }
+        Ok(())
+    }
+
+    // Size in bytes of an allocation, read from a register
+    fn allocation_size(
+        &self,
+        pc: usize,
+        register: usize,
+        allow_zero: bool,
+    ) -> Result<usize, VmError> {
+        let size = self.registers[register];
+        match usize::try_from(size) {
+            Ok(0) if !allow_zero => Err(VmError::InvalidAllocationSize { pc, size }),
+            Ok(size) => Ok(size),
+            Err(_) => Err(VmError::InvalidAllocationSize { pc, size }),
+        }
+    }
+
+    // Number of bytes held in a register, for the instructions that copy to or from the heap
+    fn byte_count(&self, pc: usize, register: usize) -> Result<usize, VmError> {
+        let len = self.registers[register];
+        usize::try_from(len).map_err(|_| VmError::InvalidLength { pc, len })
+    }
+
+    // The heap can only grow up to the maximum heap size and the first device, and
+    // addresses must fit a register
+    fn heap_limit(&self) -> usize {
+        let limit = self.max_heap_size.min(i32::MAX as usize);
+        self.bus.lowest_base().map_or(limit, |base| limit.min(base))
+    }
+
+    // Address of the allocated block in a register, for FREE and REALOC
+    fn block_address(&self, pc: usize, register: usize) -> Result<usize, VmError> {
+        let address = self.registers[register];
+        match usize::try_from(address) {
+            Ok(a) if self.allocator.block_size(a).is_some() => Ok(a),
+            Ok(a) if self.allocator.is_freed(a, 1) => Err(VmError::DoubleFree { pc, address: a }),
+            _ => Err(VmError::InvalidFree { pc, address }),
+        }
+    }
+
+    // Allocates a zeroed block of `size` bytes, growing the heap when needed
+    fn allocate(&mut self, pc: usize, size: usize) -> Result<usize, VmError> {
+        let limit = self.heap_limit();
+        let heap_len = self.heap.len();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record_allocator(&self.allocator);
+        }
+        let base =
+            self.allocator
+                .allocate(size, heap_len, limit)
+                .ok_or(VmError::HeapLimitExceeded {
+                    pc,
+                    requested: heap_len + size,
+                })?;
+        if base + size > heap_len {
+            self.resize_heap(base + size);
+        }
+        // A reused block still holds the bytes of its previous owner
+        if base < heap_len {
+            self.write_heap(base, &vec![0; size]);
+        }
+        Ok(base)
+    }
+
+    // Releases an allocated block
+    fn free(&mut self, address: usize) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record_allocator(&self.allocator);
+        }
+        self.allocator.free(address);
+    }
+
+    // Writes bytes into the heap, journaling the overwritten ones
+    fn write_heap(&mut self, address: usize, bytes: &[u8]) {
+        if let Some(journal) = self.journal.as_mut() {
+            for (i, &previous) in self.heap[address..address + bytes.len()].iter().enumerate() {
+                journal.record_heap(address + i, previous);
+            }
+        }
+        self.heap[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // Calls a host function. Its heap writes are journaled by comparing the heap
+    // before and after the call
+    fn syscall(&mut self, pc: usize, number: u16) -> Result<(), VmError> {
+        let syscall = self
+            .syscalls
+            .get_mut(&number)
+            .ok_or(VmError::UnknownSyscall { pc, number })?;
+        let heap_before = self.journal.as_ref().map(|_| self.heap.clone());
+        let mut context = SyscallContext {
+            registers: &mut self.registers,
+            float_registers: &mut self.float_registers,
+            heap: &mut self.heap,
+            pc,
+        };
+        let result = syscall.call(&mut context);
+        if let (Some(journal), Some(before)) = (self.journal.as_mut(), heap_before) {
+            for (address, (&previous, &byte)) in before.iter().zip(&self.heap).enumerate() {
+                if previous != byte {
+                    journal.record_heap(address, previous);
+                }
+            }
+        }
+        result.map_err(|message| VmError::SyscallFailed {
+            pc,
+            number,
+            message,
+        })
+    }
+
+    // Reads a single byte, so that nothing after what the program uses is taken from the
+    // input. Returns `None` at the end of the input
+    fn read_byte(&mut self, pc: usize) -> Result<Option<u8>, VmError> {
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(input_error(pc, e)),
+            }
+        }
+    }
+
+    // Reads a line without its line ending. Returns `None` at the end of the input
+    fn read_line(&mut self, pc: usize) -> Result<Option<Vec<u8>>, VmError> {
+        let mut line = vec![];
+        loop {
+            match self.read_byte(pc)? {
+                Some(b'\n') => break,
+                Some(byte) => line.push(byte),
+                None if line.is_empty() => return Ok(None),
+                None => break,
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    fn process_context(&mut self, pc: usize) -> Result<&mut ProcessContext, VmError> {
+        self.process.as_mut().ok_or(VmError::NoScheduler { pc })
+    }
+
+    // Takes the oldest message, journaling it. The mailbox must not be empty
+    fn receive(&mut self) -> Message {
+        let message = self.mailbox.pop_front().unwrap();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record_message(&message);
+        }
+        message
+    }
+
+    fn push(&mut self, pc: usize, value: i32) -> Result<(), VmError> {
+        if self.stack.len() >= self.max_stack_size {
+            return Err(VmError::StackOverflow { pc });
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // Pops a value, journaling it
+    fn pop(&mut self, pc: usize) -> Result<i32, VmError> {
+        let value = self.stack.pop().ok_or(VmError::StackUnderflow { pc })?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record_pop(value);
+        }
+        Ok(value)
+    }
+
+    fn state(&self) -> VmState<'_> {
+        VmState {
+            registers: &self.registers,
+            float_registers: &self.float_registers,
+            heap: &self.heap,
+            stack: &self.stack,
+            pc: self.pc,
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+            interrupts: &self.interrupts,
+        }
+    }
+
+    // Executes until the program halts, runs off its end, hits a breakpoint or
+    // watchpoint, or fails
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        self.decode_if_changed();
+        loop {
+            if let Some(reason) = self.step(true)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    // Executes at most `max_instructions` instructions, entering an interrupt handler
+    // counting as one. The VM keeps its state, so calling it again continues exactly where
+    // the previous call stopped
+    pub fn run_for(&mut self, max_instructions: usize) -> RunStatus {
+        self.decode_if_changed();
+        for _ in 0..max_instructions {
+            match self.step(true) {
+                Ok(Some(reason)) => return RunStatus::Exited(reason),
+                Ok(None) => {}
+                Err(e) => return RunStatus::Error(e),
+            }
+        }
+        RunStatus::BudgetExhausted
+    }
+
+    // Executes a single instruction, even if there is a breakpoint on it
+    pub fn run_once(&mut self) -> Result<ExitReason, VmError> {
+        Ok(self.step(false)?.unwrap_or(ExitReason::Stepped))
+    }
+
+    // Executes one instruction, checking breakpoints first when asked to and the
+    // watchpoints afterwards
+    fn step(&mut self, check_breakpoints: bool) -> Result<Option<ExitReason>, VmError> {
+        if self.require_verification && !self.is_verified() {
+            self.verify().map_err(VmError::VerificationFailed)?;
+        }
+        if check_breakpoints
+            && self.resume_pc != Some(self.pc)
+            && self.breakpoints.contains(&self.pc)
+        {
+            self.resume_pc = Some(self.pc);
+            return Ok(Some(ExitReason::Stopped(StoppedAt::Breakpoint(self.pc))));
+        }
+        self.resume_pc = None;
+
+        if self.watchpoints.is_empty() {
+            return self.execute_instruction();
+        }
+
+        let pc = self.pc;
+        let before: Vec<_> = self.watchpoints.iter().map(|w| w.read(self)).collect();
+        let result = self.execute_instruction()?;
+        let changed = self
+            .watchpoints
+            .iter()
+            .zip(before)
+            .find(|(watch, value)| watch.read(self) != *value);
+        match changed {
+            Some((&watch, _)) => Ok(Some(ExitReason::Stopped(StoppedAt::Watch { pc, watch }))),
+            None => Ok(result),
+        }
+    }
+
+    pub fn add_byte(&mut self, byte: u8) {
+        self.program.push(byte);
+        self.decoded.clear();
+        self.verified = false;
+    }
+
+    pub fn add_bytes(&mut self, mut b: Vec<u8>) {
+        self.program.append(&mut b);
+        self.decoded.clear();
+        self.verified = false;
+    }
+
+    // Replaces the program and decodes it ahead of execution
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.verified = false;
+        self.predecode();
+    }
+
+    // Decodes the whole program once, so that the instructions do not have to be decoded
+    // from bytes every time they are executed. Offsets that are not reached by decoding from
+    // the start, like the middle of an instruction, are still decoded when jumped to, so the
+    // behaviour does not change. Every change to the program drops the decoded copy, and
+    // `run` and `run_for` decode the program again before executing it
+    pub fn predecode(&mut self) {
+        self.decoded = predecode(&self.program);
+    }
+
+    fn decode_if_changed(&mut self) {
+        if self.decoded.len() != self.program.len() {
+            self.predecode();
+        }
+    }
+
+    // Runs the verifier on the program, and decodes it like `predecode`. The checks that
+    // depend on the values of the registers, like heap bounds and computed jump targets,
+    // are still made while executing
+    pub fn verify(&mut self) -> Result<(), Vec<VerifyError>> {
+        verify(&self.program)?;
+        self.predecode();
+        self.verified = true;
+        Ok(())
+    }
+
+    // When required, the program is verified before executing its first instruction,
+    // and again whenever it changes. A program that fails makes the run return
+    // `VmError::VerificationFailed`
+    pub fn set_require_verification(&mut self, required: bool) {
+        self.require_verification = required;
+    }
+
+    fn is_verified(&self) -> bool {
+        self.verified && self.decoded.len() == self.program.len()
+    }
+
+    // Returns `Some` when the execution must stop
+    fn execute_instruction(&mut self) -> Result<Option<ExitReason>, VmError> {
+        if self.interrupts.is_deliverable() {
+            return self.enter_interrupt();
+        }
+
+        // The program counter must be within the program
+        if self.pc == self.program.len() {
+            return Ok(Some(ExitReason::EndOfProgram));
+        }
+        if self.pc > self.program.len() {
+            return Err(VmError::PcOutOfBounds { pc: self.pc });
+        }
+
+        // Offset of the instruction, used to report errors
+        let pc = self.pc;
+        let cached = if self.decoded.len() == self.program.len() {
+            self.decoded[pc]
+        } else {
+            None
+        };
+        let instruction = match cached {
+            Some(instruction) => instruction,
+            None => decode(&self.program, pc)?,
+        };
+        let opcode = instruction.opcode;
+
+        if let Some(mut journal) = self.journal.take() {
+            journal.begin(&self.state());
+            self.journal = Some(journal);
+        }
+
+        // Without an observer this is a single check of the option
+        let result = match self.observer.take() {
+            Some(mut observer) => {
+                observer.on_instruction(pc, opcode, &self.state());
+                let result = self.execute(pc, instruction);
+                if matches!(result, Ok(reason) if reason != Some(ExitReason::Blocked)) {
+                    observer.after_instruction(pc, opcode, &self.state());
+                }
+                self.observer = Some(observer);
+                result
+            }
+            None => self.execute(pc, instruction),
+        };
+
+        // A receive without a message did not run; it is tried again on the next run
+        if result == Ok(Some(ExitReason::Blocked)) {
+            if let Some(journal) = self.journal.as_mut() {
+                journal.discard();
+            }
+            return result;
+        }
+        if result.is_ok() {
+            self.bus.tick();
+            self.interrupts.tick();
+        } else {
+            // Like a decoding error, a failed instruction leaves the program counter on it
+            self.pc = pc;
+        }
+        if let (Some(profiler), Ok(_)) = (self.profiler.as_mut(), &result) {
+            // Conditional jumps do not change the flag, so it is still the one they saw
+            profiler.record(pc, opcode, self.equal_flag);
+        }
+        // Failed instructions are journaled too, to rewind to the state before the error
+        if let Some(journal) = self.journal.as_mut() {
+            journal.commit(&self.registers, &self.float_registers, &self.interrupts);
+        }
+        result
+    }
+
+    // Enters the handler of the next pending interrupt, saving the program counter and
+    // then a flags word on the stack, and disables interrupts until IRET. This takes a
+    // step of its own, so the first instruction of the handler can stop on a breakpoint
+    fn enter_interrupt(&mut self) -> Result<Option<ExitReason>, VmError> {
+        let pc = self.pc;
+        if self.stack.len() + 2 > self.max_stack_size {
+            return Err(VmError::StackOverflow { pc });
+        }
+        if let Some(mut journal) = self.journal.take() {
+            journal.begin(&self.state());
+            self.journal = Some(journal);
+        }
+        if let Some(handler) = self.interrupts.take() {
+            let mut flags = FLAG_INTERRUPTS_ENABLED;
+            if self.equal_flag {
+                flags |= FLAG_EQUAL;
+            }
+            self.stack.push(pc as i32);
+            self.stack.push(flags);
+            self.interrupts.enabled = false;
+            self.pc = handler;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.commit(&self.registers, &self.float_registers, &self.interrupts);
+        }
+        Ok(None)
+    }
+
+    // Executes the decoded instruction found at `pc`
+    fn execute(
+        &mut self,
+        pc: usize,
+        instruction: DecodedInstruction,
+    ) -> Result<Option<ExitReason>, VmError> {
+        let [r1, r2, r3] = instruction.registers;
+        // Move to the next instruction; jumps overwrite it
+        self.pc = pc + instruction.len;
+
+        match instruction.opcode {
+            Opcode::LOAD => {
+                // Cast the number as our registers are i32
+                self.registers[r1] = instruction.immediate as i32;
+            }
+            Opcode::ADD => {
+                self.registers[r3] = self
+                    .arithmetic
+                    .add(self.registers[r1], self.registers[r2])
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            Opcode::SUB => {
+                self.registers[r3] = self
+                    .arithmetic
+                    .sub(self.registers[r1], self.registers[r2])
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            Opcode::MUL => {
+                self.registers[r3] = self
+                    .arithmetic
+                    .mul(self.registers[r1], self.registers[r2])
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            Opcode::DIV => {
+                let val1 = self.registers[r1];
+                let val2 = self.registers[r2];
+                if val2 == 0 {
+                    return Err(VmError::DivideByZero { pc });
+                }
+                let quotient = self.arithmetic.div(val1, val2);
+                let remainder = self.arithmetic.rem(val1, val2);
+                match (quotient, remainder) {
+                    (Some(quotient), Some(remainder)) => {
+                        self.registers[r3] = quotient;
+                        self.remainder = remainder;
+                    }
+                    _ => return Err(VmError::ArithmeticOverflow { pc }),
+                }
+            }
+            Opcode::REM => self.registers[r1] = self.remainder,
+            Opcode::MOD => {
+                // Same result as the remainder of DIV: -7 mod 2 is -1
+                let val1 = self.registers[r1];
+                let val2 = self.registers[r2];
+                if val2 == 0 {
+                    return Err(VmError::DivideByZero { pc });
+                }
+                self.registers[r3] = self
+                    .arithmetic
+                    .rem(val1, val2)
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            Opcode::JMP => {
+                // The register holds the memory address where to move to
+                let target = self.registers[r1];
+                self.pc = usize::try_from(target).map_err(|_| VmError::PcOutOfBounds { pc })?;
+            }
+            Opcode::JMPF => {
+                // The register holds the number of bytes to move forwards
+                let value = self.registers[r1];
+                self.pc = usize::try_from(value)
+                    .ok()
+                    .and_then(|value| self.pc.checked_add(value))
+                    .ok_or(VmError::PcOutOfBounds { pc })?;
+            }
+            Opcode::JMPB => {
+                // The register holds the number of bytes to move backwards
+                let value = self.registers[r1];
+                self.pc = usize::try_from(value)
+                    .ok()
+                    .and_then(|value| self.pc.checked_sub(value))
+                    .ok_or(VmError::PcOutOfBounds { pc })?;
+            }
+            // Comparisons store their result in the dedicated register
+            Opcode::EQ => self.equal_flag = self.registers[r1] == self.registers[r2],
+            Opcode::NEQ => self.equal_flag = self.registers[r1] != self.registers[r2],
+            Opcode::GT => self.equal_flag = self.registers[r1] > self.registers[r2],
+            Opcode::LT => self.equal_flag = self.registers[r1] < self.registers[r2],
+            Opcode::GTE => self.equal_flag = self.registers[r1] >= self.registers[r2],
+            Opcode::LTE => self.equal_flag = self.registers[r1] <= self.registers[r2],
+            Opcode::JEQ => {
+                let target = self.registers[r1];
+                if self.equal_flag {
+                    self.pc = usize::try_from(target).map_err(|_| VmError::PcOutOfBounds { pc })?;
+                }
+            }
+            Opcode::JNEQ => {
+                let target = self.registers[r1];
+                if !self.equal_flag {
+                    self.pc = usize::try_from(target).map_err(|_| VmError::PcOutOfBounds { pc })?;
+                }
+            }
+            Opcode::ALOC => {
+                let nbytes = self.allocation_size(pc, r1, true)?;
+                let new_len = self.heap.len() + nbytes;
+                if new_len > self.heap_limit() {
+                    return Err(VmError::HeapLimitExceeded {
+                        pc,
+                        requested: new_len,
+                    });
+                }
+                self.resize_heap(new_len);
+            }
+            Opcode::INC => {
+                self.registers[r1] = self
+                    .arithmetic
+                    .add(self.registers[r1], 1)
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            Opcode::DEC => {
+                self.registers[r1] = self
+                    .arithmetic
+                    .sub(self.registers[r1], 1)
+                    .ok_or(VmError::ArithmeticOverflow { pc })?;
+            }
+            // Heap accesses are big-endian. Bytes and halfwords are loaded unsigned
+            Opcode::LB => {
+                let [byte] = self.load(pc, r2, instruction.immediate)?;
+                self.registers[r1] = byte as i32;
+            }
+            Opcode::LH => {
+                let bytes = self.load(pc, r2, instruction.immediate)?;
+                self.registers[r1] = u16::from_be_bytes(bytes) as i32;
+            }
+            Opcode::LW => {
+                let bytes = self.load(pc, r2, instruction.immediate)?;
+                self.registers[r1] = i32::from_be_bytes(bytes);
+            }
+            Opcode::SB => {
+                let bytes = [self.registers[r1] as u8];
+                self.store(pc, r2, instruction.immediate, &bytes)?;
+            }
+            Opcode::SH => {
+                let bytes = (self.registers[r1] as u16).to_be_bytes();
+                self.store(pc, r2, instruction.immediate, &bytes)?;
+            }
+            Opcode::SW => {
+                let bytes = self.registers[r1].to_be_bytes();
+                self.store(pc, r2, instruction.immediate, &bytes)?;
+            }
+            Opcode::MALLOC => {
+                let size = self.allocation_size(pc, r1, false)?;
+                self.registers[r2] = self.allocate(pc, size)? as i32;
+            }
+            Opcode::FREE => {
+                let address = self.block_address(pc, r1)?;
+                self.free(address);
+            }
+            Opcode::REALOC => {
+                // The contents are copied to a new block, then the old block is freed
+                let old = self.block_address(pc, r1)?;
+                let size = self.allocation_size(pc, r2, false)?;
+                let old_size = self.allocator.block_size(old).unwrap_or(0);
+                let new = self.allocate(pc, size)?;
+                let contents = self.heap[old..old + old_size.min(size)].to_vec();
+                self.write_heap(new, &contents);
+                self.free(old);
+                self.registers[r3] = new as i32;
+            }
+            Opcode::PUSH => self.push(pc, self.registers[r1])?,
+            Opcode::POP => self.registers[r1] = self.pop(pc)?,
+            Opcode::CALL => {
+                // Return to the instruction after the call
+                self.push(pc, self.pc as i32)?;
+                self.pc = instruction.immediate as usize;
+            }
+            Opcode::RET => {
+                let target = self.pop(pc)?;
+                self.pc = usize::try_from(target).map_err(|_| VmError::PcOutOfBounds { pc })?;
+            }
+            Opcode::IVEC => {
+                let number = self.registers[r1];
+                match usize::try_from(number) {
+                    Ok(n) if n < INTERRUPT_VECTORS => {
+                        self.interrupts.vectors[n] = Some(instruction.immediate as usize)
+                    }
+                    _ => return Err(VmError::InvalidInterrupt { pc, number }),
+                }
+            }
+            Opcode::SPAWN => {
+                let registers = self.registers;
+                let context = self.process_context(pc)?;
+                let pid = context.spawn(instruction.immediate as usize, registers);
+                self.registers[r1] = pid as i32;
+            }
+            Opcode::SEND => {
+                let target = self.registers[r1];
+                let data = self.registers[r2].to_be_bytes().to_vec();
+                self.process_context(pc)?.send(pc, target, data)?;
+            }
+            Opcode::SENDM => {
+                let target = self.registers[r1];
+                let len = self.byte_count(pc, r3)?;
+                let address = self.heap_address(pc, r2, 0, len)?;
+                let data = self.heap[address..address + len].to_vec();
+                self.process_context(pc)?.send(pc, target, data)?;
+            }
+            // Without a message, the receiving instruction is tried again on the next run
+            Opcode::RECV => match self.mailbox.front() {
+                Some(message) if message.data.len() == 4 => {
+                    let message = self.receive();
+                    self.registers[r1] = i32::from_be_bytes(message.data.try_into().unwrap());
+                }
+                Some(message) => {
+                    let len = message.data.len();
+                    return Err(VmError::InvalidMessage { pc, len });
+                }
+                None => {
+                    self.pc = pc;
+                    return Ok(Some(ExitReason::Blocked));
+                }
+            },
+            Opcode::RECVM => {
+                // Stores the number of bytes written in $3. The rest of a longer message is
+                // dropped
+                let capacity = self.byte_count(pc, r2)?;
+                let address = self.heap_address(pc, r1, 0, capacity)?;
+                if self.mailbox.is_empty() {
+                    self.pc = pc;
+                    return Ok(Some(ExitReason::Blocked));
+                }
+                let mut data = self.receive().data;
+                data.truncate(capacity);
+                self.write_heap(address, &data);
+                self.registers[r3] = data.len() as i32;
+            }
+            Opcode::EI => self.interrupts.enabled = true,
+            Opcode::DI => self.interrupts.enabled = false,
+            Opcode::IRET => {
+                // Popped in the reverse order they were saved in
+                let flags = self.pop(pc)?;
+                let target = self.pop(pc)?;
+                self.equal_flag = flags & FLAG_EQUAL != 0;
+                self.interrupts.enabled = flags & FLAG_INTERRUPTS_ENABLED != 0;
+                self.pc = usize::try_from(target).map_err(|_| VmError::PcOutOfBounds { pc })?;
+            }
+            // Bitwise operations never overflow, and shifts only use the 5 lowest bits
+            // of the amount
+            Opcode::AND => self.registers[r3] = self.registers[r1] & self.registers[r2],
+            Opcode::OR => self.registers[r3] = self.registers[r1] | self.registers[r2],
+            Opcode::XOR => self.registers[r3] = self.registers[r1] ^ self.registers[r2],
+            Opcode::NOT => self.registers[r2] = !self.registers[r1],
+            Opcode::SHL => {
+                self.registers[r3] = self.registers[r1].wrapping_shl(self.registers[r2] as u32)
+            }
+            Opcode::SHR => {
+                let value = self.registers[r1] as u32;
+                self.registers[r3] = value.wrapping_shr(self.registers[r2] as u32) as i32;
+            }
+            Opcode::SAR => {
+                self.registers[r3] = self.registers[r1].wrapping_shr(self.registers[r2] as u32)
+            }
+            // Float arithmetic follows IEEE 754: dividing by zero gives an infinity or NaN
+            Opcode::LOADF => self.float_registers[r1] = instruction.float,
+            Opcode::ADDF => {
+                self.float_registers[r3] = self.float_registers[r1] + self.float_registers[r2]
+            }
+            Opcode::SUBF => {
+                self.float_registers[r3] = self.float_registers[r1] - self.float_registers[r2]
+            }
+            Opcode::MULF => {
+                self.float_registers[r3] = self.float_registers[r1] * self.float_registers[r2]
+            }
+            Opcode::DIVF => {
+                self.float_registers[r3] = self.float_registers[r1] / self.float_registers[r2]
+            }
+            // Every comparison with NaN is false, except NEQF
+            Opcode::EQF => self.equal_flag = self.float_registers[r1] == self.float_registers[r2],
+            Opcode::NEQF => self.equal_flag = self.float_registers[r1] != self.float_registers[r2],
+            Opcode::GTF => self.equal_flag = self.float_registers[r1] > self.float_registers[r2],
+            Opcode::LTF => self.equal_flag = self.float_registers[r1] < self.float_registers[r2],
+            Opcode::GTEF => self.equal_flag = self.float_registers[r1] >= self.float_registers[r2],
+            Opcode::LTEF => self.equal_flag = self.float_registers[r1] <= self.float_registers[r2],
+            Opcode::ITOF => self.float_registers[r2] = self.registers[r1] as f64,
+            // Rounds towards zero, saturating at the i32 bounds. NaN converts to 0
+            Opcode::FTOI => self.registers[r2] = self.float_registers[r1] as i32,
+            Opcode::PRTS => {
+                let start = instruction.immediate as usize;
+                let len = self
+                    .ro_data
+                    .get(start..)
+                    .and_then(|data| data.iter().position(|&byte| byte == 0))
+                    .ok_or(VmError::InvalidString { pc, address: start })?;
+                write_output(&mut self.output, pc, &self.ro_data[start..start + len])?;
+            }
+            Opcode::SYSCALL => self.syscall(pc, instruction.immediate)?,
+            // The input opcodes set the equal flag when they read something, and clear it
+            // at the end of the input, leaving the destination untouched
+            Opcode::READI => match self.read_line(pc)? {
+                Some(line) => {
+                    let text = String::from_utf8_lossy(&line);
+                    self.registers[r1] = text.trim().parse().map_err(|_| VmError::InputFailed {
+                        pc,
+                        message: format!("{:?} is not a number", text),
+                    })?;
+                    self.equal_flag = true;
+                }
+                None => self.equal_flag = false,
+            },
+            Opcode::READB => {
+                let address = self.heap_address(pc, r1, 0, 1)?;
+                let byte = self.read_byte(pc)?;
+                if let Some(byte) = byte {
+                    self.write_heap(address, &[byte]);
+                }
+                self.equal_flag = byte.is_some();
+            }
+            Opcode::READL => {
+                // Reads into the heap at $1, up to the capacity in $2, and stores the number
+                // of bytes written in $3. The rest of a longer line is dropped
+                let capacity = self.byte_count(pc, r2)?;
+                let address = self.heap_address(pc, r1, 0, capacity)?;
+                match self.read_line(pc)? {
+                    Some(mut line) => {
+                        line.truncate(capacity);
+                        self.write_heap(address, &line);
+                        self.registers[r3] = line.len() as i32;
+                        self.equal_flag = true;
+                    }
+                    None => self.equal_flag = false,
+                }
+            }
+            Opcode::HLT => return Ok(Some(ExitReason::Halted)),
+            Opcode::IGL => {
+                return Err(VmError::IllegalOpcode {
+                    pc,
+                    byte: self.program[pc],
+                });
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn input_error(pc: usize, e: io::Error) -> VmError {
+    VmError::InputFailed {
+        pc,
+        message: e.to_string(),
+    }
+}
+
+// Writes to the output sink right away, so that the output is seen before the program ends
+fn write_output(output: &mut dyn Write, pc: usize, bytes: &[u8]) -> Result<(), VmError> {
+    output
+        .write_all(bytes)
+        .and_then(|_| output.flush())
+        .map_err(|e| VmError::OutputFailed {
+            pc,
+            message: e.to_string(),
+        })
+}
+
+// The original tests compare booleans with `assert_eq!`
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Events = Arc<Mutex<Vec<(&'static str, usize, Opcode, usize)>>>;
+
+    // Records every hook call it receives
+    struct Tracer {
+        events: Events,
+    }
+
+    impl VmObserver for Tracer {
+        fn on_instruction(&mut self, pc: usize, opcode: Opcode, state: &VmState) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(("before", pc, opcode, state.pc));
+        }
+
+        fn after_instruction(&mut self, pc: usize, opcode: Opcode, state: &VmState) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(("after", pc, opcode, state.pc));
+        }
+    }
+
+    #[test]
+    fn test_create_vm() {
+        let vm = VirtualMachine::new();
+        // check if all registers are set to 0
+        for register in vm.registers.into_iter() {
+            assert_eq!(register, 0);
+        }
+        // check if heap if empty
+        assert_eq!(vm.heap.is_empty(), true);
+        // check if pc is 0
+        assert_eq!(vm.pc, 0);
+        // check if program is empty
+        assert_eq!(vm.program.is_empty(), true);
+        // check if remainder is 0
+        assert_eq!(vm.remainder, 0);
+        // check if equal flag is false
+        assert_eq!(vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_add_byte() {
+        let mut vm = VirtualMachine::new();
+        let bytes: Vec<u8> = vec![0, 23, 58, 231, 145];
+
+        for &byte in bytes.iter() {
+            vm.add_byte(byte);
+        }
+
+        for (idx, &byte) in vm.program.iter().enumerate() {
+            assert_eq!(byte, bytes[idx]);
+        }
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::HLT as u8, 0, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::IGL as u8, 0, 0, 0];
+        test_vm.program = test_program;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::IllegalOpcode {
+                pc: 0,
+                byte: Opcode::IGL as u8
+            })
+        );
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_opcode_sb_lb() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[1] = 0x1ff;
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![Opcode::SB as u8, 1, 2, 2, Opcode::LB as u8, 3, 2, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0, 0, 0xff, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 0xff);
+    }
+
+    #[test]
+    fn test_opcode_sh_lh() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 0x12345;
+        test_vm.program = vec![Opcode::SH as u8, 1, 0, 1, Opcode::LH as u8, 3, 0, 1];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0x23, 0x45, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 0x2345);
+    }
+
+    #[test]
+    fn test_opcode_sw_lw() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[1] = -2;
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![Opcode::SW as u8, 1, 2, 0, Opcode::LW as u8, 3, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0, 0xff, 0xff, 0xff, 0xfe]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], -2);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[2] = 5;
+        test_vm.registers[3] = -1;
+        test_vm.program = vec![Opcode::LW as u8, 1, 2, 0, Opcode::SB as u8, 1, 3, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { pc: 0, address: 5 })
+        );
+        test_vm.pc = 4;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { pc: 4, address: -1 })
+        );
+    }
+
+    #[test]
+    fn test_aloc_limits() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_max_heap_size(16);
+        test_vm.registers[1] = -4;
+        test_vm.registers[2] = 17;
+        test_vm.program = vec![Opcode::ALOC as u8, 1, Opcode::ALOC as u8, 2];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidAllocationSize { pc: 0, size: -4 })
+        );
+        test_vm.pc = 2;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapLimitExceeded {
+                pc: 2,
+                requested: 17
+            })
+        );
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_malloc_free() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![1; 4];
+        test_vm.registers[1] = 8;
+        test_vm.registers[5] = 0x7f;
+        test_vm.program = vec![
+            Opcode::MALLOC as u8,
+            1,
+            2,
+            0,
+            Opcode::SB as u8,
+            5,
+            2,
+            7,
+            Opcode::FREE as u8,
+            2,
+            0,
+            0,
+            Opcode::MALLOC as u8,
+            1,
+            3,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.heap.len(), 12);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap[11], 0x7f);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.allocator().block_size(4), None);
+        // The freed block is reused, and zeroed
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 4);
+        assert_eq!(test_vm.heap, vec![1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_opcode_realoc() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 4;
+        test_vm.registers[2] = 8;
+        test_vm.registers[5] = -1;
+        test_vm.program = vec![
+            Opcode::MALLOC as u8,
+            1,
+            3,
+            0,
+            Opcode::SW as u8,
+            5,
+            3,
+            0,
+            Opcode::REALOC as u8,
+            3,
+            2,
+            4,
+        ];
+        for _ in 0..3 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.registers[4], 4);
+        assert_eq!(test_vm.heap[4..], [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(test_vm.allocator().block_size(4), Some(8));
+        assert_eq!(test_vm.allocator().block_size(0), None);
+    }
+
+    #[test]
+    fn test_allocator_errors() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_max_heap_size(8);
+        test_vm.registers[1] = 4;
+        test_vm.registers[6] = 6;
+        test_vm.program = vec![
+            Opcode::MALLOC as u8,
+            1,
+            2,
+            0,
+            Opcode::FREE as u8,
+            2,
+            0,
+            0,
+            Opcode::LB as u8,
+            3,
+            2,
+            0,
+            Opcode::FREE as u8,
+            2,
+            0,
+            0,
+            Opcode::FREE as u8,
+            6,
+            0,
+            0,
+            Opcode::MALLOC as u8,
+            0,
+            2,
+            0,
+            Opcode::MALLOC as u8,
+            7,
+            2,
+            0,
+        ];
+        test_vm.registers[7] = 9;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        let errors = [
+            VmError::UseAfterFree { pc: 8, address: 0 },
+            VmError::DoubleFree { pc: 12, address: 0 },
+            VmError::InvalidFree { pc: 16, address: 6 },
+            VmError::InvalidAllocationSize { pc: 20, size: 0 },
+            VmError::HeapLimitExceeded {
+                pc: 24,
+                requested: 13,
+            },
+        ];
+        for error in errors {
+            assert_eq!(test_vm.run_once(), Err(error));
+            // The failing instruction is skipped by hand
+            test_vm.pc += 4;
+        }
+    }
+
+    #[test]
+    fn test_step_back_allocation() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 4;
+        test_vm.registers[2] = 8;
+        test_vm.program = vec![Opcode::MALLOC as u8, 1, 3, 0, Opcode::REALOC as u8, 3, 2, 3];
+        test_vm.enable_journal(16);
+        test_vm.run_once().unwrap();
+        let allocator = test_vm.allocator().clone();
+        test_vm.run_once().unwrap();
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.allocator(), &allocator);
+        assert_eq!(test_vm.heap.len(), 4);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.allocator(), &Allocator::new());
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 7;
+        test_vm.registers[2] = -3;
+        test_vm.program = vec![
+            Opcode::PUSH as u8,
+            1,
+            0,
+            0,
+            Opcode::PUSH as u8,
+            2,
+            0,
+            0,
+            Opcode::POP as u8,
+            3,
+            0,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.stack(), &[7, -3]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], -3);
+        assert_eq!(test_vm.stack(), &[7]);
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![
+            Opcode::CALL as u8,
+            0,
+            8,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            1,
+            0,
+            0,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.stack(), &[4]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 1);
+        assert!(test_vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_stack_errors() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_max_stack_size(1);
+        test_vm.program = vec![Opcode::PUSH as u8, 1, 0, 0, Opcode::CALL as u8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.run_once(), Err(VmError::StackOverflow { pc: 4 }));
+
+        test_vm.stack.clear();
+        test_vm.pc = 0;
+        test_vm.program = vec![Opcode::POP as u8, 1, 0, 0, Opcode::RET as u8, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 0 }));
+        assert_eq!(test_vm.pc, 0);
+        test_vm.pc = 4;
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 4 }));
+    }
+
+    #[test]
+    fn test_step_back_stack() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.stack = vec![1, 2];
+        test_vm.program = vec![
+            Opcode::POP as u8,
+            3,
+            0,
+            0,
+            Opcode::POP as u8,
+            3,
+            0,
+            0,
+            Opcode::CALL as u8,
+            0,
+            12,
+            0,
+            Opcode::HLT as u8,
+        ];
+        test_vm.enable_journal(16);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.stack(), &[12]);
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert!(test_vm.stack().is_empty());
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.stack(), &[1, 2]);
+    }
+
+    #[test]
+    fn test_opcode_and_or_xor() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 0b1100;
+        test_vm.registers[2] = 0b1010;
+        test_vm.program = vec![
+            Opcode::AND as u8,
+            1,
+            2,
+            3,
+            Opcode::OR as u8,
+            1,
+            2,
+            4,
+            Opcode::XOR as u8,
+            1,
+            2,
+            5,
+        ];
+        test_vm.run_for(3);
+        assert_eq!(test_vm.registers[3], 0b1000);
+        assert_eq!(test_vm.registers[4], 0b1110);
+        assert_eq!(test_vm.registers[5], 0b0110);
+    }
+
+    #[test]
+    fn test_opcode_not() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 0x0f;
+        test_vm.program = vec![Opcode::NOT as u8, 1, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -16);
+    }
+
+    #[test]
+    fn test_opcode_shifts() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = -8;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 33;
+        test_vm.program = vec![
+            Opcode::SHL as u8,
+            1,
+            2,
+            4,
+            Opcode::SHR as u8,
+            1,
+            2,
+            5,
+            Opcode::SAR as u8,
+            1,
+            2,
+            6,
+            Opcode::SHL as u8,
+            1,
+            3,
+            7,
+        ];
+        test_vm.run_for(4);
+        assert_eq!(test_vm.registers[4], -16);
+        assert_eq!(test_vm.registers[5], 0x7fff_fffc);
+        assert_eq!(test_vm.registers[6], -4);
+        // Shifting by 33 shifts by 1
+        assert_eq!(test_vm.registers[7], -16);
+    }
+
+    #[test]
+    fn test_opcode_rem() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = -7;
+        test_vm.registers[2] = 2;
+        test_vm.program = vec![Opcode::DIV as u8, 1, 2, 3, Opcode::REM as u8, 4, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], -3);
+        assert_eq!(test_vm.remainder(), -1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], -1);
+    }
+
+    #[test]
+    fn test_opcode_mod() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 7;
+        test_vm.registers[2] = -2;
+        test_vm.program = vec![
+            Opcode::MOD as u8,
+            1,
+            2,
+            3,
+            Opcode::MOD as u8,
+            2,
+            1,
+            4,
+            Opcode::MOD as u8,
+            1,
+            0,
+            5,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], -2);
+        assert_eq!(test_vm.run_once(), Err(VmError::DivideByZero { pc: 8 }));
+        // The remainder of DIV is left alone
+        assert_eq!(test_vm.remainder(), 0);
+    }
+
+    #[test]
+    fn test_opcode_loadf() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::LOADF as u8, 2, 0, 0];
+        test_vm.program.extend_from_slice(&2.5_f64.to_be_bytes());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[2], 2.5);
+        assert_eq!(test_vm.pc, 12);
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.float_registers[1] = 1.5;
+        test_vm.float_registers[2] = 0.5;
+        test_vm.program = vec![
+            Opcode::ADDF as u8,
+            1,
+            2,
+            3,
+            Opcode::SUBF as u8,
+            1,
+            2,
+            4,
+            Opcode::MULF as u8,
+            1,
+            2,
+            5,
+            Opcode::DIVF as u8,
+            1,
+            2,
+            6,
+            Opcode::DIVF as u8,
+            1,
+            0,
+            7,
+        ];
+        test_vm.run_for(5);
+        assert_eq!(test_vm.float_registers[3], 2.0);
+        assert_eq!(test_vm.float_registers[4], 1.0);
+        assert_eq!(test_vm.float_registers[5], 0.75);
+        assert_eq!(test_vm.float_registers[6], 3.0);
+        assert_eq!(test_vm.float_registers[7], f64::INFINITY);
+    }
+
+    #[test]
+    fn test_float_comparisons() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.float_registers[1] = 1.5;
+        test_vm.float_registers[2] = 0.5;
+        test_vm.float_registers[3] = f64::NAN;
+        let cases = [
+            (Opcode::EQF, 1, 1, true),
+            (Opcode::NEQF, 1, 2, true),
+            (Opcode::GTF, 1, 2, true),
+            (Opcode::LTF, 1, 2, false),
+            (Opcode::GTEF, 2, 2, true),
+            (Opcode::LTEF, 1, 2, false),
+            (Opcode::EQF, 3, 3, false),
+            (Opcode::NEQF, 3, 3, true),
+        ];
+        for (opcode, a, b, expected) in cases {
+            test_vm.pc = 0;
+            test_vm.program = vec![opcode as u8, a, b, 0];
+            test_vm.run_once().unwrap();
+            assert_eq!(test_vm.equal_flag, expected, "{:?} ${} ${}", opcode, a, b);
+        }
+    }
+
+    #[test]
+    fn test_float_conversions() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = -3;
+        test_vm.float_registers[2] = -2.75;
+        test_vm.float_registers[3] = 1e20;
+        test_vm.program = vec![
+            Opcode::ITOF as u8,
+            1,
+            1,
+            0,
+            Opcode::FTOI as u8,
+            2,
+            4,
+            0,
+            Opcode::FTOI as u8,
+            3,
+            5,
+            0,
+        ];
+        test_vm.run_for(3);
+        assert_eq!(test_vm.float_registers[1], -3.0);
+        assert_eq!(test_vm.registers[4], -2);
+        assert_eq!(test_vm.registers[5], i32::MAX);
+    }
+
+    #[test]
+    fn test_step_back_float() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.float_registers[1] = 4.0;
+        test_vm.program = vec![Opcode::ADDF as u8, 1, 1, 1];
+        test_vm.enable_journal(4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[1], 8.0);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.float_registers[1], 4.0);
+    }
+
+    #[test]
+    fn test_opcode_prts() {
+        let mut test_vm = VirtualMachine::new();
+        let output = SharedOutput::new();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.set_ro_data(b"hi\0there\0oops".to_vec());
+        test_vm.program = vec![
+            Opcode::PRTS as u8,
+            0,
+            3,
+            0,
+            Opcode::PRTS as u8,
+            0,
+            9,
+            0,
+            Opcode::PRTS as u8,
+            0,
+            20,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(output.to_string_lossy(), "there");
+        // The string must be terminated
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidString { pc: 4, address: 9 })
+        );
+        test_vm.pc = 8;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidString { pc: 8, address: 20 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_syscall() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 20;
+        // Doubles $1 into $2 and marks the heap
+        let double = |context: &mut SyscallContext| {
+            context.registers[2] = context.registers[1] * 2;
+            context.heap[3] = 0xaa;
+            Ok(())
+        };
+        let fail = |_: &mut SyscallContext| Err("no storage".to_string());
+        assert!(test_vm.register_syscall(1, Box::new(double)).is_none());
+        test_vm.register_syscall(300, Box::new(fail));
+        test_vm.program = vec![
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::SYSCALL as u8,
+            1,
+            44,
+            0,
+            Opcode::SYSCALL as u8,
+            0,
+            2,
+            0,
+        ];
+        test_vm.enable_journal(4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 40);
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0xaa]);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::SyscallFailed {
+                pc: 4,
+                number: 300,
+                message: "no storage".to_string()
+            })
+        );
+        test_vm.pc = 8;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::UnknownSyscall { pc: 8, number: 2 })
+        );
+
+        // Undoing the call restores what it changed
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.heap, vec![0; 4]);
+        assert!(test_vm.unregister_syscall(1).is_some());
+    }
+
+    #[test]
+    fn test_output_sink() {
+        let mut test_vm = VirtualMachine::new();
+        let output = SharedOutput::new();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.set_ro_data(b"hi\0".to_vec());
+        test_vm.program = vec![Opcode::PRTS as u8, 0, 0, 0, Opcode::HLT as u8];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        // HLT prints nothing
+        assert_eq!(output.to_string_lossy(), "hi");
+
+        // A failing sink makes the program fail
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        test_vm.set_output(Box::new(Broken));
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::OutputFailed {
+                pc: 0,
+                message: "disk full".to_string()
+            })
+        );
+        // Halting does not touch the sink
+        test_vm.pc = 4;
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_opcode_readi() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_input(Box::new(io::Cursor::new(b" -42\n7\r\nnope\n".to_vec())));
+        test_vm.registers[3] = 5;
+        test_vm.program = vec![
+            Opcode::READI as u8,
+            1,
+            0,
+            0,
+            Opcode::READI as u8,
+            2,
+            0,
+            0,
+            Opcode::READI as u8,
+            3,
+            0,
+            0,
+            Opcode::READI as u8,
+            3,
+            0,
+            0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], -42);
+        assert!(test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 7);
+        assert!(matches!(
+            test_vm.run_once(),
+            Err(VmError::InputFailed { pc: 8, .. })
+        ));
+        // End of input
+        test_vm.pc = 12;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        assert_eq!(test_vm.registers[3], 5);
+    }
+
+    #[test]
+    fn test_opcode_readb() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_input(Box::new(io::Cursor::new(b"ab".to_vec())));
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![
+            Opcode::READB as u8,
+            1,
+            0,
+            0,
+            Opcode::READB as u8,
+            0,
+            0,
+            0,
+            Opcode::READB as u8,
+            0,
+            0,
+            0,
+            Opcode::READB as u8,
+            2,
+            0,
+            0,
+        ];
+        for _ in 0..3 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.heap, vec![b'b', 0, b'a', 0]);
+        assert!(!test_vm.equal_flag);
+        assert!(matches!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { pc: 12, .. })
+        ));
+    }
+
+    #[test]
+    fn test_input_is_read_up_to_the_line() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_input(Box::new(io::Cursor::new(
+            b"7\nleft for the REPL\n".to_vec(),
+        )));
+        test_vm.program = vec![Opcode::READI as u8, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 7);
+        let mut rest = String::new();
+        let mut input = test_vm.set_input(Box::new(io::empty()));
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "left for the REPL\n");
+    }
+
+    #[test]
+    fn test_opcode_readl() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_input(Box::new(io::Cursor::new(b"hey\nlonger line\n".to_vec())));
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 5;
+        test_vm.program = vec![
+            Opcode::READL as u8,
+            1,
+            2,
+            3,
+            Opcode::READL as u8,
+            0,
+            2,
+            4,
+            Opcode::READL as u8,
+            0,
+            2,
+            5,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(&test_vm.heap[2..5], b"hey");
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], 5);
+        assert_eq!(&test_vm.heap[..5], b"longe");
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+
+        test_vm.registers[2] = -1;
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidLength { pc: 0, len: -1 })
+        );
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut test_vm = VirtualMachine::new();
+        let output = SharedOutput::new();
+        let framebuffer = Framebuffer::new(2, 2);
+        test_vm.heap = vec![0; 4];
+        test_vm
+            .map_device(16, Box::new(Console::new(Box::new(output.clone()))))
+            .unwrap();
+        test_vm
+            .map_device(20, Box::new(framebuffer.clone()))
+            .unwrap();
+        test_vm.map_device(24, Box::new(Timer::new())).unwrap();
+        test_vm.registers[1] = 16;
+        test_vm.registers[2] = 'A' as i32;
+        test_vm.registers[3] = 0x0102;
+        test_vm.program = vec![
+            Opcode::SB as u8,
+            2,
+            1,
+            0,
+            Opcode::SH as u8,
+            3,
+            1,
+            6,
+            Opcode::LH as u8,
+            4,
+            1,
+            5,
+            Opcode::LW as u8,
+            5,
+            1,
+            8,
+            Opcode::SB as u8,
+            2,
+            1,
+            0,
+            Opcode::SW as u8,
+            2,
+            1,
+            6,
+        ];
+        for _ in 0..4 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(output.to_string_lossy(), "A");
+        assert_eq!(framebuffer.pixels(), vec![0, 0, 1, 2]);
+        assert_eq!(framebuffer.pixel(1, 0), Some(0));
+        assert_eq!(test_vm.registers[4], 1);
+        // The timer counts the instructions before the load
+        assert_eq!(test_vm.registers[5], 3);
+        // The heap is left alone
+        assert_eq!(test_vm.heap, vec![0; 4]);
+
+        // An access must fit in one device
+        test_vm.pc = 20;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 20,
+                address: 22
+            })
+        );
+        assert!(test_vm.unmap_device(16).is_some());
+        test_vm.pc = 16;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 16,
+                address: 16
+            })
+        );
+    }
+
+    #[test]
+    fn test_device_limits_heap() {
+        struct Broken;
+        impl Device for Broken {
+            fn size(&self) -> usize {
+                1
+            }
+            fn read(&mut self, _offset: usize) -> Result<u8, String> {
+                Err("unplugged".to_string())
+            }
+            fn write(&mut self, _offset: usize, _value: u8) -> Result<(), String> {
+                Ok(())
+            }
+        }
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 4];
+        assert_eq!(
+            test_vm
+                .map_device(2, Box::new(Broken))
+                .map_err(|e| e.to_string()),
+            Err("device at 2 overlaps the heap of 4 bytes".to_string())
+        );
+        test_vm.map_device(8, Box::new(Broken)).unwrap();
+        test_vm.registers[1] = 5;
+        test_vm.registers[2] = 8;
+        test_vm.program = vec![Opcode::ALOC as u8, 1, 0, 0, Opcode::LB as u8, 3, 2, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapLimitExceeded {
+                pc: 0,
+                requested: 9
+            })
+        );
+        test_vm.pc = 4;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::DeviceFailed {
+                pc: 4,
+                address: 8,
+                message: "unplugged".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            1,
+            Opcode::IVEC as u8,
+            0,
+            0,
+            20,
+            Opcode::EQ as u8,
+            1,
+            1,
+            0,
+            Opcode::EI as u8,
+            0,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            2,
+            0,
+            0,
+            Opcode::EQ as u8,
+            1,
+            2,
+            0,
+            Opcode::IRET as u8,
+            0,
+            0,
+            0,
+        ];
+        // Raised before it has a handler, so it stays pending
+        assert!(test_vm.raise_interrupt(1));
+        assert!(!test_vm.raise_interrupt(INTERRUPT_VECTORS));
+        for _ in 0..4 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.interrupts().vector(1), Some(20));
+        assert!(test_vm.equal_flag);
+
+        // Entering the handler is a step of its own
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc(), 20);
+        assert_eq!(test_vm.stack(), &[16, 3]);
+        assert!(!test_vm.interrupts().is_enabled());
+        assert!(!test_vm.interrupts().is_pending(1));
+
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[2], 1);
+        // IRET restored the flag the handler changed
+        assert!(test_vm.equal_flag);
+        assert!(test_vm.interrupts().is_enabled());
+        assert!(test_vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[5] = 12;
+        test_vm.program = vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            0,
+            Opcode::IVEC as u8,
+            0,
+            0,
+            16,
+            Opcode::EI as u8,
+            0,
+            0,
+            0,
+            Opcode::JMP as u8,
+            5,
+            0,
+            0,
+            Opcode::INC as u8,
+            2,
+            0,
+            0,
+            Opcode::IRET as u8,
+            0,
+            0,
+            0,
+        ];
+        test_vm.set_timer_interrupt(3);
+        // LOAD, IVEC and EI, then the handler every three instructions
+        assert_eq!(test_vm.run_for(3 + 4 * 3), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.registers[2], 3);
+        // The last JMP raised one more interrupt before the timer was turned off
+        test_vm.set_timer_interrupt(0);
+        assert_eq!(test_vm.run_for(30), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.registers[2], 4);
+    }
+
+    #[test]
+    fn test_interrupt_errors() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = INTERRUPT_VECTORS as i32;
+        test_vm.program = vec![
+            Opcode::IVEC as u8,
+            1,
+            0,
+            0,
+            Opcode::IVEC as u8,
+            0,
+            0,
+            0,
+            Opcode::EI as u8,
+            0,
+            0,
+            0,
+            Opcode::IRET as u8,
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidInterrupt { pc: 0, number: 16 })
+        );
+        test_vm.pc = 4;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.set_max_stack_size(1);
+        test_vm.raise_interrupt(0);
+        assert_eq!(test_vm.run_once(), Err(VmError::StackOverflow { pc: 12 }));
+        assert!(test_vm.interrupts().is_pending(0));
+        assert_eq!(test_vm.run_once(), Err(VmError::StackOverflow { pc: 12 }));
+        test_vm.interrupts.enabled = false;
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 12 }));
+    }
+
+    #[test]
+    fn test_step_back_interrupt() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![
+            Opcode::IVEC as u8,
+            0,
+            0,
+            8,
+            Opcode::EI as u8,
+            0,
+            0,
+            0,
+            Opcode::IRET as u8,
+            0,
+            0,
+            0,
+        ];
+        test_vm.enable_journal(16);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.raise_interrupt(0);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc(), 8);
+        assert!(test_vm.interrupts().is_enabled());
+
+        // Undo IRET, then the entry into the handler
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.stack(), &[8, 2]);
+        assert!(!test_vm.interrupts().is_enabled());
+        assert!(test_vm.step_back());
+        assert!(test_vm.stack().is_empty());
+        assert!(test_vm.interrupts().is_pending(0));
+        assert!(test_vm.step_back());
+        assert!(!test_vm.interrupts().is_enabled());
+    }
+
+    #[test]
+    fn test_opcode_recv() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![
+            Opcode::RECV as u8,
+            1,
+            0,
+            0,
+            Opcode::RECVM as u8,
+            0,
+            2,
+            3,
+            Opcode::RECV as u8,
+            1,
+            0,
+            0,
+        ];
+        test_vm.post_message(Message {
+            sender: 1,
+            data: 7i32.to_be_bytes().to_vec(),
+        });
+        test_vm.post_message(Message {
+            sender: 2,
+            data: b"hello".to_vec(),
+        });
+        test_vm.post_message(Message {
+            sender: 2,
+            data: vec![1, 2],
+        });
+        test_vm.enable_journal(8);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 7);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, b"hel\0");
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidMessage { pc: 8, len: 2 })
+        );
+        assert_eq!(test_vm.mailbox().len(), 1);
+
+        // Undoing RECVM puts its message back
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.mailbox().len(), 2);
+        assert_eq!(test_vm.mailbox()[0].data, b"hello");
+        assert_eq!(test_vm.heap, vec![0; 4]);
+
+        test_vm.mailbox.clear();
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Ok(ExitReason::Blocked));
+        assert_eq!(test_vm.pc(), 0);
+    }
+
+    #[test]
+    fn test_blocked_recv_does_not_run() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::RECV as u8, 1, 0, 0];
+        test_vm.enable_profiling();
+        test_vm.enable_journal(8);
+        test_vm.set_timer_interrupt(2);
+        for _ in 0..3 {
+            assert_eq!(test_vm.run(), Ok(ExitReason::Blocked));
+        }
+        assert_eq!(test_vm.pc(), 0);
+        assert_eq!(test_vm.profiler().unwrap().opcode_count(Opcode::RECV), 0);
+        assert!(test_vm.journal().unwrap().is_empty());
+        assert!(!test_vm.interrupts().is_pending(TIMER_INTERRUPT));
+    }
+
+    #[test]
+    fn test_opcode_send_without_scheduler() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::SEND as u8, 1, 2, 0];
+        assert_eq!(test_vm.run_once(), Err(VmError::NoScheduler { pc: 0 }));
+    }
+
+    #[test]
+    fn test_run_exit_reasons() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::LOAD as u8, 0, 0, 1, Opcode::HLT as u8, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::Stepped));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::LOAD as u8, 0, 0, 1];
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut test_vm = VirtualMachine::new();
+        // An infinite loop: jump to the start of the program
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::JMP as u8, 1, 0, 0];
+        assert_eq!(test_vm.run_for(10), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.run_for(3), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.registers[0], 7);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.run_for(0), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.pc, 4);
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::HLT as u8, 0, 0, 0];
+        assert_eq!(test_vm.run_for(1), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.run_for(100), RunStatus::Exited(ExitReason::Halted));
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::IGL as u8];
+        assert_eq!(
+            test_vm.run_for(100),
+            RunStatus::Error(VmError::IllegalOpcode {
+                pc: 0,
+                byte: Opcode::IGL as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        test_vm.add_breakpoint(4);
+        test_vm.add_breakpoint(8);
+        assert_eq!(
+            test_vm.run(),
+            Ok(ExitReason::Stopped(StoppedAt::Breakpoint(4)))
+        );
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(
+            test_vm.run(),
+            Ok(ExitReason::Stopped(StoppedAt::Breakpoint(8)))
+        );
+        assert_eq!(test_vm.registers[0], 2);
+        assert!(test_vm.remove_breakpoint(8));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 4;
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::EQ as u8,
+            1,
+            2,
+            0,
+            Opcode::DIV as u8,
+            1,
+            2,
+            3,
+            Opcode::ALOC as u8,
+            1,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        assert!(test_vm.add_watchpoint(Watch::Register(3)));
+        assert!(test_vm.add_watchpoint(Watch::EqualFlag));
+        assert!(test_vm.add_watchpoint(Watch::Heap { start: 0, end: 2 }));
+        assert!(!test_vm.add_watchpoint(Watch::Register(32)));
+        assert_eq!(
+            test_vm.run(),
+            Ok(ExitReason::Stopped(StoppedAt::Watch {
+                pc: 4,
+                watch: Watch::EqualFlag
+            }))
+        );
+        assert_eq!(
+            test_vm.run(),
+            Ok(ExitReason::Stopped(StoppedAt::Watch {
+                pc: 8,
+                watch: Watch::Register(3)
+            }))
+        );
+        assert_eq!(
+            test_vm.run(),
+            Ok(ExitReason::Stopped(StoppedAt::Watch {
+                pc: 12,
+                watch: Watch::Heap { start: 0, end: 2 }
+            }))
+        );
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_observer() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_observer(Box::new(Tracer {
+            events: events.clone(),
+        }));
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::HLT as u8, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("before", 0, Opcode::INC, 0),
+                ("after", 0, Opcode::INC, 4),
+                ("before", 4, Opcode::HLT, 4),
+                ("after", 4, Opcode::HLT, 5),
+            ]
+        );
+        assert!(test_vm.take_observer().is_some());
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 0;
+        // Decrement $1 until it is 0
+        test_vm.program = vec![
+            Opcode::DEC as u8,
+            1,
+            0,
+            0,
+            Opcode::EQ as u8,
+            1,
+            3,
+            0,
+            Opcode::JNEQ as u8,
+            2,
+            0,
+            0,
+        ];
+        test_vm.enable_profiling();
+        // JNEQ is two bytes long, so falling through runs into the padding: a HLT
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        let profiler = test_vm.disable_profiling().unwrap();
+        assert_eq!(profiler.opcode_count(Opcode::DEC), 3);
+        let jneq = profiler.offset_count(8).unwrap();
+        assert_eq!((jneq.count, jneq.taken, jneq.not_taken), (3, 2, 1));
+        assert!(test_vm.profiler().is_none());
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = 6;
+        test_vm.registers[2] = 4;
+        test_vm.registers[4] = 2;
+        test_vm.program = vec![
+            Opcode::EQ as u8,
+            1,
+            2,
+            0,
+            Opcode::DIV as u8,
+            1,
+            2,
+            3,
+            Opcode::ALOC as u8,
+            1,
+            Opcode::ALOC as u8,
+            4,
+            Opcode::HLT as u8,
+        ];
+        test_vm.enable_journal(16);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.heap.len(), 8);
+        assert_eq!(test_vm.journal().unwrap().len(), 5);
+
+        // Undo the HLT and the second ALOC
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 10);
+        assert_eq!(test_vm.heap.len(), 6);
+
+        // Go back to the comparison
+        assert!(!test_vm.run_back_to(100));
+        assert_eq!(test_vm.pc, 10);
+        assert!(test_vm.run_back_to(0));
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.registers[3], 0);
+        assert_eq!(test_vm.remainder, 0);
+        assert!(test_vm.heap.is_empty());
+        assert!(!test_vm.step_back());
+
+        // Undoing a store restores the heap
+        test_vm.program = vec![Opcode::SW as u8, 1, 2, 0];
+        test_vm.heap = vec![9; 4];
+        test_vm.registers[2] = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 6]);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.heap, vec![9; 4]);
+        test_vm.heap.clear();
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![
+            Opcode::EQ as u8,
+            1,
+            2,
+            0,
+            Opcode::DIV as u8,
+            1,
+            2,
+            3,
+            Opcode::ALOC as u8,
+            1,
+            Opcode::ALOC as u8,
+            4,
+            Opcode::HLT as u8,
+        ];
+
+        // Replaying gives the same result
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[3], 1);
+        assert_eq!(test_vm.remainder, 2);
+        assert_eq!(test_vm.heap.len(), 8);
+    }
+
+    #[test]
+    fn test_step_back_after_error() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::IGL as u8];
+        test_vm.enable_journal(16);
+        assert!(test_vm.run().is_err());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_predecoded_program() {
+        // The JMP lands in the middle of the second LOAD, on an INC
+        let program = vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            9,
+            Opcode::JMP as u8,
+            1,
+            Opcode::LOAD as u8,
+            3,
+            0,
+            Opcode::INC as u8,
+            4,
+            0,
+            0,
+            Opcode::HLT as u8,
+        ];
+        let mut bytes_vm = VirtualMachine::new();
+        bytes_vm.program = program.clone();
+        let mut decoded_vm = VirtualMachine::new();
+        decoded_vm.load_program(program);
+        assert!(decoded_vm.decoded[9].is_none());
+
+        assert_eq!(bytes_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(decoded_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(decoded_vm.registers, bytes_vm.registers);
+        assert_eq!(decoded_vm.pc, bytes_vm.pc);
+        assert_eq!(decoded_vm.registers[4], 1);
+        assert_eq!(decoded_vm.registers[3], 0);
+
+        // Appending to the program drops the decoded copy
+        decoded_vm.add_byte(Opcode::HLT as u8);
+        assert!(decoded_vm.decoded.is_empty());
+    }
+
+    #[test]
+    fn test_decoded_program_follows_changes() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.load_program(vec![Opcode::INC as u8, 1, 0, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        // A program of the same length does not run the decoded copy of the previous one
+        test_vm.load_program(vec![Opcode::INC as u8, 2, 0, 0]);
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[1], 1);
+        assert_eq!(test_vm.registers[2], 1);
+
+        // Appended bytes are decoded when the run starts
+        test_vm.add_bytes(vec![Opcode::INC as u8, 3, 0, 0]);
+        assert_eq!(test_vm.run_for(1), RunStatus::BudgetExhausted);
+        assert_eq!(test_vm.decoded.len(), 8);
+        assert!(test_vm.decoded[4].is_some());
+        assert_eq!(test_vm.registers[3], 1);
+    }
+
+    // Compares a loop executed from the decoded program with the same loop decoded at
+    // every instruction. Run it with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_predecoded_loop() {
+        use std::time::Instant;
+
+        const ITERATIONS: i32 = 5_000_000;
+        // Counts $0 up to $1, jumping back to the start while it is lower
+        let program = vec![
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::LT as u8,
+            0,
+            1,
+            0,
+            Opcode::JEQ as u8,
+            2,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        let time = |decoded: bool| {
+            let mut test_vm = VirtualMachine::new();
+            test_vm.set_output(Box::new(SharedOutput::new()));
+            test_vm.add_bytes(program.clone());
+            test_vm.registers[1] = ITERATIONS;
+            let start = Instant::now();
+            if decoded {
+                assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+            } else {
+                // Stepping does not decode the program ahead
+                while test_vm.run_once() == Ok(ExitReason::Stepped) {}
+            }
+            assert_eq!(test_vm.registers[0], ITERATIONS);
+            start.elapsed()
+        };
+        let bytes = time(false);
+        let decoded = time(true);
+        println!(
+            "{} iterations: {:?} from bytes, {:?} decoded ahead ({:.2}x)",
+            ITERATIONS,
+            bytes,
+            decoded,
+            bytes.as_secs_f64() / decoded.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn test_require_verification() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_require_verification(true);
+        test_vm.program = vec![Opcode::INC as u8, 0, 0, 0, Opcode::ADD as u8, 0, 0, 40];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::VerificationFailed(vec![
+                VerifyError::InvalidRegister { pc: 4, index: 40 }
+            ]))
+        );
+        // Nothing was executed
+        assert_eq!(test_vm.registers[0], 0);
+
+        test_vm.program[7] = 1;
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert!(test_vm.is_verified());
+        assert_eq!(test_vm.registers[1], 2);
+
+        // Changing the program requires a new verification
+        test_vm.add_byte(200);
+        assert!(!test_vm.is_verified());
+        assert!(test_vm.run().is_err());
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 15;
+        test_vm.program = vec![Opcode::LOAD as u8, 0, 0, 1, Opcode::DIV as u8, 3, 6, 19];
+        assert_eq!(test_vm.run(), Err(VmError::DivideByZero { pc: 4 }));
+        assert_eq!(test_vm.registers[19], 0);
+        // Running again does not go past the failed instruction
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.run(), Err(VmError::DivideByZero { pc: 4 }));
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        let program = vec![
+            Opcode::ADD as u8,
+            0,
+            1,
+            2,
+            Opcode::INC as u8,
+            0,
+            0,
+            0,
+            Opcode::MUL as u8,
+            1,
+            1,
+            3,
+        ];
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = i32::MAX;
+        test_vm.program = program.clone();
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[2], -2);
+        assert_eq!(test_vm.registers[0], i32::MIN);
+        assert_eq!(test_vm.registers[3], 1);
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = i32::MAX;
+        test_vm.program = program.clone();
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        assert_eq!(test_vm.registers[0], i32::MAX);
+        assert_eq!(test_vm.registers[3], i32::MAX);
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_arithmetic_mode(ArithmeticMode::Trapping);
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 0;
+        test_vm.program = program;
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 4 }));
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        assert_eq!(test_vm.registers[0], i32::MAX);
+    }
+
+    #[test]
+    fn test_div_overflow() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[1] = i32::MIN;
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![Opcode::DIV as u8, 1, 2, 3];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], i32::MIN);
+        assert_eq!(test_vm.remainder, 0);
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.set_arithmetic_mode(ArithmeticMode::Trapping);
+        test_vm.registers[1] = i32::MIN;
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![Opcode::DIV as u8, 1, 2, 3];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ArithmeticOverflow { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::ADD as u8, 1, 32, 2];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidRegister { pc: 0, index: 32 })
+        );
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.program = vec![Opcode::HLT as u8, Opcode::LOAD as u8, 0, 1];
+        test_vm.pc = 1;
+        assert_eq!(test_vm.run(), Err(VmError::TruncatedInstruction { pc: 1 }));
+    }
+
+    #[test]
+    fn test_pc_out_of_bounds() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[7] = 10;
+        test_vm.program = vec![Opcode::JMPB as u8, 7, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::PcOutOfBounds { pc: 0 }));
+
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 20;
+        test_vm.program = vec![Opcode::JMPF as u8, 8, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::PcOutOfBounds { pc: 22 }));
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::LOAD as u8, 0, 1, 244]; // 256 * 1 + 244 = 500 :-)
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[4] = 5;
+        test_vm.registers[9] = 3;
+        let test_program = vec![Opcode::ADD as u8, 4, 9, 17];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[17], 8);
+    }
+
+    #[test]
+    fn test_opcode_sub() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 5;
+        test_vm.registers[3] = 3;
+        let test_program = vec![Opcode::SUB as u8, 8, 3, 7];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[7], 2);
+    }
+
+    #[test]
+    fn test_opcode_mul() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 9;
+        test_vm.registers[3] = 5;
+        let test_program = vec![Opcode::MUL as u8, 8, 3, 12];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[12], 45);
+    }
+
+    #[test]
+    fn test_opcode_div() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 15;
+        test_vm.registers[6] = 4;
+        let test_program = vec![Opcode::DIV as u8, 3, 6, 19];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[19], 3);
+        assert_eq!(test_vm.remainder, 3);
+    }
+
+    #[test]
+    fn test_opcode_jmp() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 7;
+        let test_program = vec![Opcode::JMP as u8, 3, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 7);
+    }
+
+    #[test]
+    fn test_opcode_jmpf() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 20;
+        let test_program = vec![Opcode::JMPF as u8, 8, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 22);
+    }
+
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[7] = 2;
+        let test_program = vec![Opcode::LOAD as u8, 0, 0, 0, Opcode::JMPB as u8, 7, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_eq() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 10;
+        test_vm.registers[7] = 10;
+        let test_program = vec![Opcode::EQ as u8, 3, 7, 0, Opcode::EQ as u8, 3, 5, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_opcode_neq() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 10;
+        test_vm.registers[7] = 10;
+        let test_program = vec![Opcode::NEQ as u8, 3, 7, 0, Opcode::NEQ as u8, 3, 5, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+    }
+
+    #[test]
+    fn test_opcode_gt() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 5;
+        test_vm.registers[7] = 10;
+        let test_program = vec![Opcode::GT as u8, 3, 7, 0, Opcode::GT as u8, 7, 3, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+    }
+
+    #[test]
+    fn test_opcode_lt() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 5;
+        test_vm.registers[7] = 10;
+        let test_program = vec![Opcode::LT as u8, 3, 7, 0, Opcode::LT as u8, 7, 3, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+    }
+
+    #[test]
+    fn test_opcode_gte() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 5;
+        test_vm.registers[7] = 10;
+        test_vm.registers[9] = 10;
+        let test_program = vec![
+            Opcode::GTE as u8,
+            3,
+            7,
+            0,
+            Opcode::GTE as u8,
+            7,
+            3,
+            0,
+            Opcode::GTE as u8,
+            7,
+            9,
+            0,
+        ];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+    }
+
+    #[test]
+    fn test_opcode_lte() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[3] = 5;
+        test_vm.registers[7] = 10;
+        test_vm.registers[9] = 10;
+        let test_program = vec![
+            Opcode::LTE as u8,
+            3,
+            7,
+            0,
+            Opcode::LTE as u8,
+            7,
+            3,
+            0,
+            Opcode::LTE as u8,
+            7,
+            9,
+            0,
+        ];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.equal_flag, true);
+    }
+
+    #[test]
+    fn test_opcode_jeq() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 13;
+        test_vm.registers[1] = 5;
+        test_vm.registers[2] = 5;
+        let test_program = vec![Opcode::EQ as u8, 1, 2, 0, Opcode::JEQ as u8, 8, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 13);
+    }
+
+    #[test]
+    fn test_opcode_jneq() {
+        let mut test_vm = VirtualMachine::new();
+        test_vm.registers[8] = 9;
+        test_vm.registers[1] = 8;
+        test_vm.registers[2] = 5;
+        let test_program = vec![Opcode::EQ as u8, 1, 2, 0, Opcode::JNEQ as u8, 8, 0, 0];
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 9);
+    }
+
+    #[test]
+    fn test_opcode_aloc() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::ALOC as u8, 1, Opcode::ALOC as u8, 2];
+        test_vm.registers[1] = 100;
+        test_vm.registers[2] = 700;
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 100);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 800);
+    }
+
+    #[test]
+    fn test_opcode_inc() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::INC as u8, 1, 0, 0];
+        test_vm.registers[1] = 50;
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 51);
+    }
+
+    #[test]
+    fn test_opcode_dec() {
+        let mut test_vm = VirtualMachine::new();
+        let test_program = vec![Opcode::DEC as u8, 1, 0, 0];
+        test_vm.registers[1] = 50;
+        test_vm.program = test_program;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 49);
+    }
+}
@@ -0,0 +1,26 @@
+use crate::instruction::Opcode;
+use crate::vm::Interrupts;
+
+// Read-only view of the VM state handed to observers
+#[derive(Debug)]
+pub struct VmState<'a> {
+    pub registers: &'a [i32; 32],
+    pub float_registers: &'a [f64; 32],
+    pub heap: &'a [u8],
+    pub stack: &'a [i32],
+    pub pc: usize,
+    pub remainder: i32,
+    pub equal_flag: bool,
+    pub interrupts: &'a Interrupts,
+}
+
+// Hooks called by the VM around every decoded instruction, to plug in tracers, coverage
+// collectors or assertions. Both methods do nothing by default
+pub trait VmObserver: Send {
+    // Called before the instruction at `pc` is executed. `state.pc` is still `pc`
+    fn on_instruction(&mut self, _pc: usize, _opcode: Opcode, _state: &VmState) {}
+
+    // Called after the instruction at `pc` was executed without error. `state.pc` is the
+    // offset of the next instruction
+    fn after_instruction(&mut self, _pc: usize, _opcode: Opcode, _state: &VmState) {}
+}
@@ -0,0 +1,55 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// In-memory output sink whose clones share the same buffer. Give one clone to the VM
+// with `set_output` and keep the other to read what the program printed
+#[derive(Clone, Debug, Default)]
+pub struct SharedOutput {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SharedOutput {
+    pub fn new() -> SharedOutput {
+        SharedOutput::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    // The output decoded as UTF-8, with invalid sequences replaced
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.lock().unwrap()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.lock().unwrap().clear();
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_output() {
+        let output = SharedOutput::new();
+        let mut writer = output.clone();
+        write!(writer, "hello {}", 42).unwrap();
+        assert_eq!(output.contents(), b"hello 42");
+        assert_eq!(output.to_string_lossy(), "hello 42");
+        output.clear();
+        assert!(output.contents().is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::assembler::symbols::SymbolTable;
+use crate::instruction::Opcode;
+use std::collections::HashMap;
+use std::fmt;
+
+// Execution counts of a single program offset
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OffsetCount {
+    pub count: u64,
+    pub taken: u64,     // only for JEQ/JNEQ: times the jump was taken
+    pub not_taken: u64, // only for JEQ/JNEQ: times the jump fell through
+}
+
+// Counts the instructions executed while profiling is enabled on the VM
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    opcodes: HashMap<Opcode, u64>,
+    offsets: HashMap<usize, (Opcode, OffsetCount)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    // Records an executed instruction. `equal_flag` is the flag the instruction saw,
+    // which tells whether a conditional jump was taken
+    pub fn record(&mut self, pc: usize, opcode: Opcode, equal_flag: bool) {
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        let (_, counts) = self
+            .offsets
+            .entry(pc)
+            .or_insert_with(|| (opcode, OffsetCount::default()));
+        counts.count += 1;
+        match opcode {
+            Opcode::JEQ if equal_flag => counts.taken += 1,
+            Opcode::JNEQ if !equal_flag => counts.taken += 1,
+            Opcode::JEQ | Opcode::JNEQ => counts.not_taken += 1,
+            _ => {}
+        }
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).copied().unwrap_or(0)
+    }
+
+    pub fn offset_count(&self, offset: usize) -> Option<&OffsetCount> {
+        self.offsets.get(&offset).map(|(_, counts)| counts)
+    }
+
+    // Builds the report, hottest first. Offsets are shown relative to the closest
+    // label when a symbol table is given
+    pub fn report(&self, symbols: Option<&SymbolTable>) -> ProfileReport {
+        let mut opcodes: Vec<(Opcode, u64)> = self.opcodes.iter().map(|(o, c)| (*o, *c)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| (a.0 as u8).cmp(&(b.0 as u8))));
+
+        let mut hot_spots: Vec<HotSpot> = self
+            .offsets
+            .iter()
+            .map(|(&offset, (opcode, counts))| HotSpot {
+                offset,
+                label: symbols
+                    .and_then(|s| s.nearest_label(offset as u32))
+                    .map(|(name, delta)| match delta {
+                        0 => name.to_string(),
+                        _ => format!("{}+{}", name, delta),
+                    }),
+                opcode: *opcode,
+                counts: counts.clone(),
+            })
+            .collect();
+        hot_spots.sort_by(|a, b| {
+            b.counts
+                .count
+                .cmp(&a.counts.count)
+                .then_with(|| a.offset.cmp(&b.offset))
+        });
+
+        ProfileReport { opcodes, hot_spots }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotSpot {
+    pub offset: usize,
+    pub label: Option<String>, // e.g. `loop+4`, when the symbols are known
+    pub opcode: Opcode,
+    pub counts: OffsetCount,
+}
+
+// Profile sorted by execution count, descending
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileReport {
+    pub opcodes: Vec<(Opcode, u64)>,
+    pub hot_spots: Vec<HotSpot>,
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Executions per opcode:")?;
+        for (opcode, count) in &self.opcodes {
+            writeln!(f, "  {:<6} {:>10}", format!("{:?}", opcode), count)?;
+        }
+        writeln!(f, "Hot spots:")?;
+        for spot in &self.hot_spots {
+            write!(
+                f,
+                "  {:>6} {:<16} {:<6} {:>10}",
+                spot.offset,
+                spot.label.as_deref().unwrap_or(""),
+                format!("{:?}", spot.opcode),
+                spot.counts.count
+            )?;
+            if let Opcode::JEQ | Opcode::JNEQ = spot.opcode {
+                write!(
+                    f,
+                    " (taken {}, not taken {})",
+                    spot.counts.taken, spot.counts.not_taken
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbols::{Symbol, SymbolType};
+
+    #[test]
+    fn test_record() {
+        let mut profiler = Profiler::new();
+        profiler.record(0, Opcode::INC, false);
+        profiler.record(4, Opcode::JEQ, true);
+        profiler.record(0, Opcode::INC, false);
+        profiler.record(4, Opcode::JEQ, false);
+        profiler.record(8, Opcode::JNEQ, false);
+        assert_eq!(profiler.opcode_count(Opcode::INC), 2);
+        assert_eq!(profiler.opcode_count(Opcode::HLT), 0);
+        assert_eq!(
+            profiler.offset_count(4),
+            Some(&OffsetCount {
+                count: 2,
+                taken: 1,
+                not_taken: 1
+            })
+        );
+        assert_eq!(profiler.offset_count(8).unwrap().taken, 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut profiler = Profiler::new();
+        profiler.record(0, Opcode::LOAD, false);
+        for _ in 0..3 {
+            profiler.record(4, Opcode::INC, false);
+            profiler.record(8, Opcode::DEC, false);
+        }
+        profiler.record(8, Opcode::DEC, false);
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 4));
+
+        let report = profiler.report(Some(&symbols));
+        assert_eq!(
+            report.opcodes,
+            vec![(Opcode::DEC, 4), (Opcode::INC, 3), (Opcode::LOAD, 1)]
+        );
+        let spots: Vec<_> = report
+            .hot_spots
+            .iter()
+            .map(|s| (s.offset, s.label.clone()))
+            .collect();
+        assert_eq!(
+            spots,
+            vec![
+                (8, Some("loop+4".to_string())),
+                (4, Some("loop".to_string())),
+                (0, None)
+            ]
+        );
+        assert!(report.to_string().contains("loop+4"));
+    }
+}
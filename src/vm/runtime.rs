@@ -0,0 +1,174 @@
+use crate::vm::{RunStatus, VirtualMachine};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// What a VM run on the runtime ended with: the VM in its final state, and how the run
+// stopped. A run without an instruction budget never ends in `BudgetExhausted`
+pub struct Outcome {
+    pub vm: VirtualMachine,
+    pub status: RunStatus,
+}
+
+// Gives the outcome of a VM handed to the runtime
+pub struct VmHandle {
+    receiver: Receiver<thread::Result<Outcome>>,
+}
+
+impl VmHandle {
+    // Waits for the VM to stop. Like `std::thread::JoinHandle::join`, the error holds the
+    // payload of a panic, for example one of a host function
+    pub fn join(self) -> thread::Result<Outcome> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("the runtime stopped before the VM")))
+    }
+}
+
+// Runs independent VMs on a pool of worker threads, each VM on one thread at a time.
+// Dropping the runtime waits for the VMs it was given to stop
+pub struct Runtime {
+    sender: Option<Sender<Job>>,  // queue of the VMs to run, closed on drop
+    workers: Vec<JoinHandle<()>>, // threads taking VMs from the queue
+}
+
+impl Runtime {
+    // Starts `threads` workers, or one per available core when it is 0
+    pub fn new(threads: usize) -> Runtime {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("flavia-worker-{}", i))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("failed to start a runtime worker")
+            })
+            .collect();
+        Runtime {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    // Runs the VM until it halts, runs off the end of its program, stops or fails
+    pub fn spawn(&self, vm: VirtualMachine) -> VmHandle {
+        self.submit(vm, |vm| match vm.run() {
+            Ok(reason) => RunStatus::Exited(reason),
+            Err(e) => RunStatus::Error(e),
+        })
+    }
+
+    // Runs the VM for at most `max_instructions` instructions, so that a program that
+    // loops forever does not hold a worker
+    pub fn spawn_for(&self, vm: VirtualMachine, max_instructions: usize) -> VmHandle {
+        self.submit(vm, move |vm| vm.run_for(max_instructions))
+    }
+
+    fn submit<F>(&self, mut vm: VirtualMachine, run: F) -> VmHandle
+    where
+        F: FnOnce(&mut VirtualMachine) -> RunStatus + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job = Box::new(move || {
+            // A panic loses the VM, but not the worker
+            let outcome = panic::catch_unwind(AssertUnwindSafe(move || {
+                let status = run(&mut vm);
+                Outcome { vm, status }
+            }));
+            // The handle may have been dropped already
+            let _ = sender.send(outcome);
+        });
+        if let Some(queue) = &self.sender {
+            // The workers only stop once the queue is closed
+            let _ = queue.send(job);
+        }
+        VmHandle { receiver }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers once it is empty
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::{ExitReason, SharedOutput, SyscallContext, VmError};
+
+    fn vm(program: Vec<u8>) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.set_output(Box::new(SharedOutput::new()));
+        vm.program = program;
+        vm
+    }
+
+    #[test]
+    fn test_runtime_runs_vms() {
+        let runtime = Runtime::new(4);
+        assert_eq!(runtime.threads(), 4);
+        let handles: Vec<_> = (0..32)
+            .map(|i| {
+                let mut vm = vm(vec![Opcode::MUL as u8, 1, 1, 2, Opcode::HLT as u8, 0, 0, 0]);
+                vm.registers[1] = i;
+                runtime.spawn(vm)
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            let outcome = handle.join().unwrap();
+            assert_eq!(outcome.status, RunStatus::Exited(ExitReason::Halted));
+            assert_eq!(outcome.vm.registers[2], (i * i) as i32);
+        }
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let runtime = Runtime::new(2);
+        let failing = runtime.spawn(vm(vec![Opcode::DIV as u8, 1, 2, 3]));
+        let looping = runtime.spawn_for(vm(vec![Opcode::JMP as u8, 0]), 100);
+        let mut panicking = vm(vec![Opcode::SYSCALL as u8, 0, 1, 0]);
+        panicking.register_syscall(
+            1,
+            Box::new(|_: &mut SyscallContext| -> Result<(), String> {
+                panic!("host function bug")
+            }),
+        );
+        let panicking = runtime.spawn(panicking);
+
+        assert_eq!(
+            failing.join().unwrap().status,
+            RunStatus::Error(VmError::DivideByZero { pc: 0 })
+        );
+        assert_eq!(looping.join().unwrap().status, RunStatus::BudgetExhausted);
+        assert!(panicking.join().is_err());
+        // The worker survived the panic
+        let outcome = runtime.spawn(vm(vec![])).join().unwrap();
+        assert_eq!(outcome.status, RunStatus::Exited(ExitReason::EndOfProgram));
+    }
+}
@@ -0,0 +1,591 @@
+use crate::vm::{ExitReason, RunStatus, VirtualMachine, VmError};
+
+// A message sent by SEND or SENDM, waiting in the mailbox of its target until RECV or
+// RECVM takes it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub sender: usize, // id of the process that sent it
+    pub data: Vec<u8>, // the value of SEND as 4 big-endian bytes, or the heap bytes of SENDM
+}
+
+// A process started by SPAWN, waiting to be added to the scheduler
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SpawnRequest {
+    pub pid: usize,           // id written in the destination register of SPAWN
+    pub start: usize,         // offset the new process starts at
+    pub registers: [i32; 32], // registers of the parent when it executed SPAWN
+}
+
+// What SPAWN and SEND reach while a process runs its quantum: the process ids, and the
+// processes and messages the scheduler takes care of after the quantum
+#[derive(Debug, Default)]
+pub(crate) struct ProcessContext {
+    pid: usize,                  // id of the running process
+    next_pid: usize,             // id of the next process started
+    spawned: Vec<SpawnRequest>,  // processes started by SPAWN
+    sent: Vec<(usize, Message)>, // messages sent by SEND and SENDM, with their target
+}
+
+impl ProcessContext {
+    pub(crate) fn new(pid: usize, next_pid: usize) -> ProcessContext {
+        ProcessContext {
+            pid,
+            next_pid,
+            spawned: vec![],
+            sent: vec![],
+        }
+    }
+
+    pub(crate) fn spawn(&mut self, start: usize, registers: [i32; 32]) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        self.spawned.push(SpawnRequest {
+            pid,
+            start,
+            registers,
+        });
+        pid
+    }
+
+    // Sends the bytes to the process in a register, which must have been started already
+    pub(crate) fn send(&mut self, pc: usize, target: i32, data: Vec<u8>) -> Result<(), VmError> {
+        match usize::try_from(target) {
+            Ok(t) if t < self.next_pid => {
+                let message = Message {
+                    sender: self.pid,
+                    data,
+                };
+                self.sent.push((t, message));
+                Ok(())
+            }
+            _ => Err(VmError::UnknownProcess { pc, pid: target }),
+        }
+    }
+}
+
+// Where a process is in its life
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProcessStatus {
+    Ready,              // Waiting for its next quantum
+    Blocked,            // Waiting in RECV or RECVM until a message arrives
+    Exited(ExitReason), // Stopped without an error, and is not scheduled anymore
+    Failed(VmError),    // Stopped with an error, and is not scheduled anymore
+}
+
+// A VM run by the scheduler, under its process id
+pub struct Process {
+    pid: usize,
+    vm: VirtualMachine,
+    status: ProcessStatus,
+}
+
+impl Process {
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    pub fn status(&self) -> &ProcessStatus {
+        &self.status
+    }
+
+    // Messages to processes that stopped are kept, but never read
+    fn deliver(&mut self, message: Message) {
+        self.vm.post_message(message);
+        if self.status == ProcessStatus::Blocked {
+            self.status = ProcessStatus::Ready;
+        }
+    }
+}
+
+// How the scheduler picks the process that runs next
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Policy {
+    // Every ready process in turn, for a full quantum each
+    #[default]
+    RoundRobin,
+    // A ready process, for between 1 instruction and a full quantum, both drawn from a
+    // generator seeded with the value. The same seed gives the same interleaving, so a
+    // concurrency bug found with one can be reproduced
+    Seeded(u64),
+}
+
+// Configures the VM of a process started by SPAWN, given its process id, before it runs
+pub type SpawnHook = Box<dyn FnMut(usize, &mut VirtualMachine) + Send>;
+
+// Runs many VMs, one quantum of instructions at a time. Processes are never removed, so
+// their final state can be looked at once they stopped
+pub struct Scheduler {
+    processes: Vec<Process>,       // by process id
+    next_pid: usize,               // id of the next process added
+    quantum: usize,                // instructions a process runs before the next one is picked
+    policy: Policy,                // how the process that runs next is picked
+    cursor: usize,                 // index where the round-robin looks for the next process
+    rng: XorShift,                 // generator of the seeded policy
+    spawn_hook: Option<SpawnHook>, // called on every VM started by SPAWN
+}
+
+impl Scheduler {
+    pub fn new(quantum: usize) -> Scheduler {
+        Scheduler {
+            processes: vec![],
+            next_pid: 0,
+            quantum: quantum.max(1),
+            policy: Policy::RoundRobin,
+            cursor: 0,
+            rng: XorShift::new(0),
+            spawn_hook: None,
+        }
+    }
+
+    pub fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    // A quantum of 0 is taken as 1
+    pub fn set_quantum(&mut self, quantum: usize) {
+        self.quantum = quantum.max(1);
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    // Changes the policy. Setting a seeded policy restarts its generator
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        if let Policy::Seeded(seed) = policy {
+            self.rng = XorShift::new(seed);
+        }
+    }
+
+    // Sets what is done to the VM of every process started by SPAWN. The output, input and
+    // host functions of a VM cannot be copied, so this is where the processes get theirs,
+    // for example clones of a `SharedOutput`. Returns the previous hook
+    pub fn set_spawn_hook(&mut self, hook: SpawnHook) -> Option<SpawnHook> {
+        self.spawn_hook.replace(hook)
+    }
+
+    // Adds a process running the VM, returning its id
+    pub fn spawn(&mut self, vm: VirtualMachine) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        self.processes.push(Process {
+            pid,
+            vm,
+            status: ProcessStatus::Ready,
+        });
+        pid
+    }
+
+    pub fn process(&self, pid: usize) -> Option<&Process> {
+        self.processes.get(pid)
+    }
+
+    pub fn process_mut(&mut self, pid: usize) -> Option<&mut Process> {
+        self.processes.get_mut(pid)
+    }
+
+    pub fn processes(&self) -> impl ExactSizeIterator<Item = &Process> + '_ {
+        self.processes.iter()
+    }
+
+    // Whether any process is still ready to run
+    pub fn has_ready(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|p| p.status == ProcessStatus::Ready)
+    }
+
+    // Whether no process can run anymore while some are waiting for a message, which can
+    // only come from the host then
+    pub fn is_deadlocked(&self) -> bool {
+        !self.has_ready()
+            && self
+                .processes
+                .iter()
+                .any(|p| p.status == ProcessStatus::Blocked)
+    }
+
+    // Puts a message from the host in the mailbox of a process, waking it up when it was
+    // blocked. Returns false when there is no such process
+    pub fn post_message(&mut self, pid: usize, message: Message) -> bool {
+        match self.processes.get_mut(pid) {
+            Some(process) => {
+                process.deliver(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs the next process for one quantum, then adds the processes it spawned and
+    // delivers the messages it sent. A process that blocks in RECV gives up the rest of its
+    // quantum. Returns its id, or `None` when no process is ready
+    pub fn run_slice(&mut self) -> Option<usize> {
+        let (index, budget) = self.pick()?;
+        let next_pid = self.next_pid;
+        let process = &mut self.processes[index];
+        let pid = process.pid;
+        process.vm.process = Some(ProcessContext::new(pid, next_pid));
+        process.status = match process.vm.run_for(budget) {
+            RunStatus::BudgetExhausted => ProcessStatus::Ready,
+            RunStatus::Exited(ExitReason::Blocked) => ProcessStatus::Blocked,
+            RunStatus::Exited(reason) => ProcessStatus::Exited(reason),
+            RunStatus::Error(e) => ProcessStatus::Failed(e),
+        };
+        let context = process.vm.process.take().unwrap_or_default();
+
+        for request in context.spawned {
+            let mut vm = self.processes[index].vm.spawned(&request);
+            if let Some(hook) = self.spawn_hook.as_mut() {
+                hook(request.pid, &mut vm);
+            }
+            self.processes.push(Process {
+                pid: request.pid,
+                vm,
+                status: ProcessStatus::Ready,
+            });
+        }
+        self.next_pid = self.next_pid.max(context.next_pid);
+        for (target, message) in context.sent {
+            self.processes[target].deliver(message);
+        }
+        Some(pid)
+    }
+
+    // Runs slices until no process is ready. It does not return while a process loops
+    pub fn run(&mut self) {
+        while self.run_slice().is_some() {}
+    }
+
+    // Runs at most `max_slices` slices. Returns whether a process is still ready
+    pub fn run_for(&mut self, max_slices: usize) -> bool {
+        for _ in 0..max_slices {
+            if self.run_slice().is_none() {
+                return false;
+            }
+        }
+        self.has_ready()
+    }
+
+    // Index of the process that runs next, and for how many instructions
+    fn pick(&mut self) -> Option<(usize, usize)> {
+        let ready: Vec<usize> = (0..self.processes.len())
+            .filter(|&i| self.processes[i].status == ProcessStatus::Ready)
+            .collect();
+        if ready.is_empty() {
+            return None;
+        }
+        match self.policy {
+            Policy::RoundRobin => {
+                let index = ready
+                    .iter()
+                    .copied()
+                    .find(|&i| i >= self.cursor)
+                    .unwrap_or(ready[0]);
+                self.cursor = index + 1;
+                Some((index, self.quantum))
+            }
+            Policy::Seeded(_) => {
+                let index = ready[self.rng.below(ready.len())];
+                Some((index, 1 + self.rng.below(self.quantum)))
+            }
+        }
+    }
+}
+
+// Xorshift pseudo-random generator, good enough to shuffle the interleaving and
+// reproducible from its seed
+#[derive(Clone, Debug)]
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The generator would only ever return 0 from a zero state
+        XorShift {
+            state: if seed == 0 {
+                0x2545_f491_4f6c_dd1d
+            } else {
+                seed
+            },
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // A number below `n`, which must not be 0
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+impl VirtualMachine {
+    // The VM of a process started by SPAWN: the same program, read-only data, limits and
+    // arithmetic mode as its parent, with a copy of the parent registers and an empty heap
+    // and stack. Its output, input and host functions are the ones of a new VM until the
+    // spawn hook of the scheduler sets them
+    fn spawned(&self, request: &SpawnRequest) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.registers = request.registers;
+        vm.pc = request.start;
+        vm.program = self.program.clone();
+        vm.decoded = self.decoded.clone();
+        vm.require_verification = self.require_verification;
+        vm.verified = self.verified;
+        vm.ro_data = self.ro_data.clone();
+        vm.max_heap_size = self.max_heap_size;
+        vm.max_stack_size = self.max_stack_size;
+        vm.arithmetic = self.arithmetic;
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::SharedOutput;
+    use std::sync::{Arc, Mutex};
+
+    // Counts up in $1 forever
+    fn counter() -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.registers[2] = 0;
+        vm.program = vec![Opcode::INC as u8, 1, 0, 0, Opcode::JMP as u8, 2];
+        vm
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut scheduler = Scheduler::new(4);
+        let a = scheduler.spawn(counter());
+        let b = scheduler.spawn(counter());
+        let mut halting = VirtualMachine::new();
+        halting.program = vec![Opcode::HLT as u8, 0, 0, 0];
+        let c = scheduler.spawn(halting);
+        assert_eq!((a, b, c), (0, 1, 2));
+
+        let order: Vec<_> = (0..5).map(|_| scheduler.run_slice().unwrap()).collect();
+        assert_eq!(order, vec![0, 1, 2, 0, 1]);
+        assert_eq!(
+            scheduler.process(c).unwrap().status(),
+            &ProcessStatus::Exited(ExitReason::Halted)
+        );
+        // Two quanta of four instructions each, half of them INC
+        assert_eq!(scheduler.process(a).unwrap().vm().registers[1], 4);
+        assert_eq!(scheduler.process(b).unwrap().vm().registers[1], 4);
+        assert!(scheduler.run_for(10));
+    }
+
+    #[test]
+    fn test_seeded_policy() {
+        let slices = |seed| {
+            let mut scheduler = Scheduler::new(8);
+            scheduler.set_policy(Policy::Seeded(seed));
+            for _ in 0..3 {
+                scheduler.spawn(counter());
+            }
+            let order: Vec<_> = (0..20).map(|_| scheduler.run_slice().unwrap()).collect();
+            let counts: Vec<_> = scheduler.processes().map(|p| p.vm().registers[1]).collect();
+            (order, counts)
+        };
+        assert_eq!(slices(42), slices(42));
+        assert_ne!(slices(42), slices(7));
+    }
+
+    #[test]
+    fn test_message_passing() {
+        // Adds up the values it receives in $2
+        let mut consumer = VirtualMachine::new();
+        consumer.registers[3] = 0;
+        consumer.program = vec![
+            Opcode::RECV as u8,
+            1,
+            0,
+            0,
+            Opcode::ADD as u8,
+            1,
+            2,
+            2,
+            Opcode::JMP as u8,
+            3,
+        ];
+        let mut producer = VirtualMachine::new();
+        producer.registers[1] = 0;
+        producer.registers[2] = 7;
+        producer.program = vec![
+            Opcode::SEND as u8,
+            1,
+            2,
+            0,
+            Opcode::SEND as u8,
+            1,
+            2,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+
+        let mut scheduler = Scheduler::new(4);
+        let consumer = scheduler.spawn(consumer);
+        let producer = scheduler.spawn(producer);
+        assert_eq!(scheduler.run_slice(), Some(consumer));
+        assert_eq!(
+            scheduler.process(consumer).unwrap().status(),
+            &ProcessStatus::Blocked
+        );
+        assert_eq!(scheduler.run_slice(), Some(producer));
+        assert_eq!(scheduler.process(consumer).unwrap().vm().mailbox().len(), 2);
+        assert_eq!(
+            scheduler.process(consumer).unwrap().status(),
+            &ProcessStatus::Ready
+        );
+
+        scheduler.run();
+        let consumer_vm = scheduler.process(consumer).unwrap().vm();
+        assert_eq!(consumer_vm.registers[2], 14);
+        // Nothing will ever send the consumer another message
+        assert!(scheduler.is_deadlocked());
+
+        let message = Message {
+            sender: producer,
+            data: 1i32.to_be_bytes().to_vec(),
+        };
+        assert!(scheduler.post_message(consumer, message.clone()));
+        assert!(!scheduler.post_message(9, message));
+        assert!(!scheduler.is_deadlocked());
+        scheduler.run();
+        assert_eq!(scheduler.process(consumer).unwrap().vm().registers[2], 15);
+    }
+
+    #[test]
+    fn test_opcode_sendm() {
+        let mut sender = VirtualMachine::new();
+        sender.heap = b"hi".to_vec();
+        sender.registers[1] = 1;
+        sender.registers[3] = 2;
+        sender.registers[4] = 9;
+        sender.program = vec![Opcode::SENDM as u8, 1, 2, 3, Opcode::SENDM as u8, 4, 2, 3];
+        let mut receiver = VirtualMachine::new();
+        receiver.heap = vec![0; 4];
+        receiver.registers[2] = 4;
+        receiver.program = vec![Opcode::RECVM as u8, 1, 2, 3];
+
+        let mut scheduler = Scheduler::new(4);
+        let sender = scheduler.spawn(sender);
+        let receiver = scheduler.spawn(receiver);
+        scheduler.run();
+        assert_eq!(
+            scheduler.process(sender).unwrap().status(),
+            &ProcessStatus::Failed(VmError::UnknownProcess { pc: 4, pid: 9 })
+        );
+        let receiver_vm = scheduler.process(receiver).unwrap().vm();
+        assert_eq!(receiver_vm.heap(), b"hi\0\0");
+        assert_eq!(receiver_vm.registers[3], 2);
+
+        // Negative lengths and capacities are errors, not empty messages
+        let mut sender = VirtualMachine::new();
+        sender.registers[3] = -2;
+        sender.program = vec![Opcode::SENDM as u8, 1, 2, 3];
+        let mut receiver = VirtualMachine::new();
+        receiver.registers[2] = -1;
+        receiver.program = vec![Opcode::RECVM as u8, 1, 2, 3];
+        let mut scheduler = Scheduler::new(4);
+        let sender = scheduler.spawn(sender);
+        let receiver = scheduler.spawn(receiver);
+        scheduler.run();
+        assert_eq!(
+            scheduler.process(sender).unwrap().status(),
+            &ProcessStatus::Failed(VmError::InvalidLength { pc: 0, len: -2 })
+        );
+        assert_eq!(
+            scheduler.process(receiver).unwrap().status(),
+            &ProcessStatus::Failed(VmError::InvalidLength { pc: 0, len: -1 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_spawn() {
+        let program = vec![
+            Opcode::SPAWN as u8,
+            1,
+            0,
+            12,
+            Opcode::SPAWN as u8,
+            2,
+            0,
+            12,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::INC as u8,
+            3,
+            0,
+            0,
+            Opcode::PRTS as u8,
+            0,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        let parent = || {
+            let mut vm = VirtualMachine::new();
+            vm.registers[3] = 5;
+            vm.set_ro_data(b"hi\0".to_vec());
+            vm.program = program.clone();
+            vm
+        };
+        let mut scheduler = Scheduler::new(16);
+        let output = SharedOutput::new();
+        let children = Arc::new(Mutex::new(vec![]));
+        let (hook_output, hook_children) = (output.clone(), Arc::clone(&children));
+        scheduler.set_spawn_hook(Box::new(move |pid, vm| {
+            vm.set_output(Box::new(hook_output.clone()));
+            hook_children.lock().unwrap().push(pid);
+        }));
+        scheduler.spawn(counter());
+        let parent_pid = scheduler.spawn(parent());
+        scheduler.run_slice();
+        scheduler.run_slice();
+        let parent_vm = scheduler.process(parent_pid).unwrap().vm();
+        assert_eq!(parent_vm.registers[1], 2);
+        assert_eq!(parent_vm.registers[2], 3);
+        assert_eq!(scheduler.processes().len(), 4);
+
+        scheduler.run_slice();
+        scheduler.run_slice();
+        scheduler.run_slice();
+        let child = scheduler.process(2).unwrap();
+        assert_eq!(child.status(), &ProcessStatus::Exited(ExitReason::Halted));
+        // The child started with the registers of its parent
+        assert_eq!(child.vm().registers[3], 6);
+        assert_eq!(child.vm().registers[1], 0);
+        // Both children printed to the sink set by the hook
+        assert_eq!(*children.lock().unwrap(), vec![2, 3]);
+        assert_eq!(output.to_string_lossy(), "hihi");
+
+        // Outside a scheduler, there is nowhere to start a process
+        assert_eq!(parent().run_once(), Err(VmError::NoScheduler { pc: 0 }));
+    }
+}
@@ -0,0 +1,500 @@
+use crate::vm::{
+    Allocator, ArithmeticMode, Interrupts, Message, VirtualMachine, DEFAULT_MAX_HEAP_SIZE,
+    DEFAULT_MAX_STACK_SIZE,
+};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// A snapshot starts with these bytes, followed by the format version.
+// All numbers are stored big-endian, like the operands in the bytecode.
+// Version 2 appends the maximum heap size and the allocator blocks, version 3 the stack
+// and its maximum size, version 4 the float registers, version 5 the read-only data,
+// version 6 the interrupt state and version 7 the mailbox
+const MAGIC: &[u8; 4] = b"FLVS";
+pub const SNAPSHOT_VERSION: u16 = 7;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),           // The file could not be read or written
+    BadMagic,                // The data is not a flavia snapshot
+    UnsupportedVersion(u16), // The snapshot was written by an unknown format version
+    Truncated,               // The data ends before the snapshot does
+    Corrupt(&'static str),   // A field holds an impossible value
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a flavia snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Corrupt(field) => write!(f, "snapshot has an invalid {}", field),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+// Reads the fields of a snapshot one after the other
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Truncated)
+    }
+
+    // A length-prefixed byte vector
+    fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // A count-prefixed list of values
+    fn values(&mut self) -> Result<Vec<i32>, SnapshotError> {
+        let count = self.usize()?;
+        let mut values = vec![];
+        for _ in 0..count {
+            values.push(self.u32()? as i32);
+        }
+        Ok(values)
+    }
+
+    // A count-prefixed list of (base address, size) heap blocks
+    fn blocks(&mut self) -> Result<Vec<(usize, usize)>, SnapshotError> {
+        let count = self.usize()?;
+        let mut blocks = vec![];
+        for _ in 0..count {
+            blocks.push((self.usize()?, self.usize()?));
+        }
+        Ok(blocks)
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn write_values(out: &mut Vec<u8>, values: &[i32]) {
+    out.extend_from_slice(&(values.len() as u64).to_be_bytes());
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_blocks(out: &mut Vec<u8>, blocks: impl ExactSizeIterator<Item = (usize, usize)>) {
+    out.extend_from_slice(&(blocks.len() as u64).to_be_bytes());
+    for (base, size) in blocks {
+        out.extend_from_slice(&(base as u64).to_be_bytes());
+        out.extend_from_slice(&(size as u64).to_be_bytes());
+    }
+}
+
+// Whether the allocator blocks, in use or freed, lie within the heap without overlapping
+fn blocks_fit(allocated: &[(usize, usize)], freed: &[(usize, usize)], heap_len: usize) -> bool {
+    let mut blocks: Vec<_> = allocated.iter().chain(freed).copied().collect();
+    blocks.sort_unstable();
+    let mut end = 0;
+    for (base, size) in blocks {
+        if base < end {
+            return false;
+        }
+        match base.checked_add(size) {
+            Some(block_end) if block_end <= heap_len => end = block_end,
+            _ => return false,
+        }
+    }
+    true
+}
+
+impl VirtualMachine {
+    // Serializes the whole execution state: registers, heap, program counter, program,
+    // remainder, equal flag, arithmetic mode, maximum heap size, allocator, stack, float
+    // registers, read-only data, interrupt state and mailbox. Breakpoints, watchpoints,
+    // observers, the profiler and mapped devices are not part of it
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        for register in &self.registers {
+            out.extend_from_slice(&register.to_be_bytes());
+        }
+        out.extend_from_slice(&(self.pc as u64).to_be_bytes());
+        out.extend_from_slice(&self.remainder.to_be_bytes());
+        out.push(self.equal_flag as u8);
+        out.push(match self.arithmetic {
+            ArithmeticMode::Wrapping => 0,
+            ArithmeticMode::Saturating => 1,
+            ArithmeticMode::Trapping => 2,
+        });
+        write_bytes(&mut out, &self.heap);
+        write_bytes(&mut out, &self.program);
+        out.extend_from_slice(&(self.max_heap_size as u64).to_be_bytes());
+        write_blocks(&mut out, self.allocator.allocated());
+        write_blocks(&mut out, self.allocator.freed());
+        out.extend_from_slice(&(self.max_stack_size as u64).to_be_bytes());
+        write_values(&mut out, &self.stack);
+        for register in &self.float_registers {
+            out.extend_from_slice(&register.to_be_bytes());
+        }
+        write_bytes(&mut out, &self.ro_data);
+        // Vectors without a handler are stored as u64::MAX
+        for vector in &self.interrupts.vectors {
+            out.extend_from_slice(&vector.map_or(u64::MAX, |v| v as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&self.interrupts.pending.to_be_bytes());
+        out.push(self.interrupts.enabled as u8);
+        out.extend_from_slice(&self.interrupts.timer_period.to_be_bytes());
+        out.extend_from_slice(&self.interrupts.timer_countdown.to_be_bytes());
+        out.extend_from_slice(&(self.mailbox.len() as u64).to_be_bytes());
+        for message in &self.mailbox {
+            out.extend_from_slice(&(message.sender as u64).to_be_bytes());
+            write_bytes(&mut out, &message.data);
+        }
+        out
+    }
+
+    // Replaces the execution state with the one in `bytes`. On error the VM is unchanged.
+    // Snapshots of older versions restore the missing fields to their defaults
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader { bytes };
+        match reader.take(MAGIC.len()) {
+            Ok(magic) if magic == MAGIC => {}
+            _ => return Err(SnapshotError::BadMagic),
+        }
+        let version = reader.u16()?;
+        if version == 0 || version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        let pc = reader.usize()?;
+        let remainder = reader.u32()? as i32;
+        let equal_flag = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Corrupt("equal flag")),
+        };
+        let arithmetic = match reader.u8()? {
+            0 => ArithmeticMode::Wrapping,
+            1 => ArithmeticMode::Saturating,
+            2 => ArithmeticMode::Trapping,
+            _ => return Err(SnapshotError::Corrupt("arithmetic mode")),
+        };
+        let heap = reader.bytes()?;
+        let program = reader.bytes()?;
+        let mut max_heap_size = DEFAULT_MAX_HEAP_SIZE;
+        let mut allocator = Allocator::new();
+        if version >= 2 {
+            max_heap_size = reader.usize()?;
+            let allocated = reader.blocks()?;
+            let freed = reader.blocks()?;
+            if !blocks_fit(&allocated, &freed, heap.len()) {
+                return Err(SnapshotError::Corrupt("allocator"));
+            }
+            for (base, size) in allocated {
+                allocator.insert_allocated(base, size);
+            }
+            for (base, size) in freed {
+                allocator.insert_freed(base, size);
+            }
+        }
+        let mut max_stack_size = DEFAULT_MAX_STACK_SIZE;
+        let mut stack = vec![];
+        if version >= 3 {
+            max_stack_size = reader.usize()?;
+            stack = reader.values()?;
+        }
+        let mut float_registers = [0.0; 32];
+        if version >= 4 {
+            for register in float_registers.iter_mut() {
+                *register = f64::from_bits(reader.u64()?);
+            }
+        }
+        let ro_data = if version >= 5 {
+            reader.bytes()?
+        } else {
+            vec![]
+        };
+        let mut interrupts = Interrupts::new();
+        if version >= 6 {
+            for vector in interrupts.vectors.iter_mut() {
+                *vector = match reader.u64()? {
+                    u64::MAX => None,
+                    v => Some(usize::try_from(v).map_err(|_| SnapshotError::Corrupt("vector"))?),
+                };
+            }
+            interrupts.pending = reader.u16()?;
+            interrupts.enabled = match reader.u8()? {
+                0 => false,
+                1 => true,
+                _ => return Err(SnapshotError::Corrupt("interrupt flag")),
+            };
+            interrupts.timer_period = reader.u32()?;
+            interrupts.timer_countdown = reader.u32()?;
+        }
+        let mut mailbox = VecDeque::new();
+        if version >= 7 {
+            for _ in 0..reader.u64()? {
+                let sender = reader.usize()?;
+                let data = reader.bytes()?;
+                mailbox.push_back(Message { sender, data });
+            }
+        }
+
+        self.registers = registers;
+        self.pc = pc;
+        self.remainder = remainder;
+        self.equal_flag = equal_flag;
+        self.arithmetic = arithmetic;
+        self.heap = heap;
+        self.program = program;
+        self.max_heap_size = max_heap_size;
+        self.allocator = allocator;
+        self.max_stack_size = max_stack_size;
+        self.stack = stack;
+        self.float_registers = float_registers;
+        self.ro_data = ro_data;
+        self.interrupts = interrupts;
+        self.mailbox = mailbox;
+        self.decoded.clear();
+        self.verified = false;
+        self.resume_pc = None;
+        // The recorded history does not lead to the restored state
+        if let Some(journal) = self.journal.as_mut() {
+            journal.clear();
+        }
+        Ok(())
+    }
+
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.snapshot())?;
+        Ok(())
+    }
+
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SnapshotError> {
+        let bytes = fs::read(path)?;
+        self.restore(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::ExitReason;
+
+    fn test_vm() -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.registers[1] = 7;
+        vm.registers[2] = -2;
+        vm.program = vec![
+            Opcode::DIV as u8,
+            1,
+            2,
+            3,
+            Opcode::ALOC as u8,
+            1,
+            0,
+            0,
+            Opcode::INC as u8,
+            3,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        vm.set_arithmetic_mode(ArithmeticMode::Trapping);
+        vm.set_max_heap_size(1024);
+        vm.set_max_stack_size(16);
+        vm.stack = vec![3, -4];
+        vm.float_registers[7] = -1.25;
+        vm.set_ro_data(b"text\0".to_vec());
+        vm.set_timer_interrupt(100);
+        vm.raise_interrupt(2);
+        vm.post_message(Message {
+            sender: 3,
+            data: vec![1, 2],
+        });
+        vm
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut vm = test_vm();
+        vm.run_once().unwrap();
+        vm.run_once().unwrap();
+        let snapshot = vm.snapshot();
+
+        let mut restored = VirtualMachine::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.heap, vm.heap);
+        assert_eq!(restored.pc, vm.pc);
+        assert_eq!(restored.program, vm.program);
+        assert_eq!(restored.remainder, vm.remainder);
+        assert_eq!(restored.equal_flag, vm.equal_flag);
+        assert_eq!(restored.arithmetic_mode(), ArithmeticMode::Trapping);
+        assert_eq!(restored.max_heap_size(), 1024);
+        assert_eq!(restored.stack(), &[3, -4]);
+        assert_eq!(restored.max_stack_size(), 16);
+        assert_eq!(restored.float_registers, vm.float_registers);
+        assert_eq!(restored.ro_data(), b"text\0");
+        assert_eq!(restored.interrupts(), vm.interrupts());
+        assert_eq!(restored.mailbox(), vm.mailbox());
+
+        // Both machines carry on identically
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(restored.run(), Ok(ExitReason::Halted));
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.pc, vm.pc);
+    }
+
+    #[test]
+    fn test_snapshot_allocator() {
+        let mut vm = VirtualMachine::new();
+        vm.registers[1] = 4;
+        vm.program = vec![
+            Opcode::MALLOC as u8,
+            1,
+            2,
+            0,
+            Opcode::MALLOC as u8,
+            1,
+            3,
+            0,
+            Opcode::FREE as u8,
+            2,
+            0,
+            0,
+        ];
+        vm.run().unwrap();
+        let mut restored = VirtualMachine::new();
+        restored.restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.allocator(), vm.allocator());
+    }
+
+    #[test]
+    fn test_restore_invalid_allocator() {
+        let mut vm = VirtualMachine::new();
+        vm.heap = vec![0; 16];
+        vm.allocator.insert_allocated(4, 8);
+        let mut restored = VirtualMachine::new();
+        restored.restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.allocator(), vm.allocator());
+
+        // A block past the end of the heap
+        let mut outside = VirtualMachine::new();
+        outside.heap = vec![0; 16];
+        outside.allocator.insert_freed(12, 8);
+        // A freed block overlapping a block in use
+        let mut overlapping = VirtualMachine::new();
+        overlapping.heap = vec![0; 16];
+        overlapping.allocator.insert_allocated(4, 8);
+        overlapping.allocator.insert_freed(8, 4);
+        for vm in [outside, overlapping] {
+            assert!(matches!(
+                restored.restore(&vm.snapshot()),
+                Err(SnapshotError::Corrupt("allocator"))
+            ));
+        }
+        // The VM keeps its previous state
+        assert_eq!(restored.allocator().block_size(4), Some(8));
+    }
+
+    #[test]
+    fn test_restore_version_1() {
+        let vm = test_vm();
+        let mut snapshot = vm.snapshot();
+        // Version 1 ends after the program
+        let end = 6 + 32 * 4 + 8 + 4 + 1 + 1 + 8 + vm.heap.len() + 8 + vm.program.len();
+        snapshot.truncate(end);
+        snapshot[5] = 1;
+        let mut restored = VirtualMachine::new();
+        restored.set_max_heap_size(1);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.program, vm.program);
+        assert_eq!(restored.max_heap_size(), DEFAULT_MAX_HEAP_SIZE);
+        assert!(restored.stack().is_empty());
+        assert_eq!(restored.float_registers, [0.0; 32]);
+        assert!(restored.ro_data().is_empty());
+        assert_eq!(restored.interrupts(), &Interrupts::new());
+        assert!(restored.mailbox().is_empty());
+    }
+
+    #[test]
+    fn test_restore_invalid() {
+        let mut vm = test_vm();
+        let snapshot = vm.snapshot();
+        assert!(matches!(vm.restore(b"nope"), Err(SnapshotError::BadMagic)));
+        assert!(matches!(
+            vm.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+        let mut future = snapshot.clone();
+        future[5] = 99;
+        assert!(matches!(
+            vm.restore(&future),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+        // A failed restore leaves the VM untouched
+        assert_eq!(vm.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let path = std::env::temp_dir().join(format!("flavia-snapshot-{}", std::process::id()));
+        let mut vm = test_vm();
+        vm.run_once().unwrap();
+        vm.save_state(&path).unwrap();
+        let mut restored = VirtualMachine::new();
+        restored.load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.snapshot(), vm.snapshot());
+    }
+}
@@ -0,0 +1,31 @@
+use std::collections::HashMap;
+
+// What a host function can reach while handling a SYSCALL. The heap can be read and
+// written but not resized, so that it stays in line with the allocator
+pub struct SyscallContext<'a> {
+    pub registers: &'a mut [i32; 32],
+    pub float_registers: &'a mut [f64; 32],
+    pub heap: &'a mut [u8],
+    pub pc: usize, // offset of the SYSCALL instruction
+}
+
+// A host function that assembly programs call with `syscall #n`, once it is registered on
+// the VM under the number `n`. Returning an error stops the program with
+// `VmError::SyscallFailed`. Host functions must be `Send` so that the VM can be moved to
+// another thread, like the ones of `Runtime`
+pub trait Syscall: Send {
+    fn call(&mut self, context: &mut SyscallContext) -> Result<(), String>;
+}
+
+// Closures can be registered directly
+impl<F> Syscall for F
+where
+    F: FnMut(&mut SyscallContext) -> Result<(), String> + Send,
+{
+    fn call(&mut self, context: &mut SyscallContext) -> Result<(), String> {
+        self(context)
+    }
+}
+
+// Host functions registered on the VM, by number
+pub type SyscallTable = HashMap<u16, Box<dyn Syscall>>;
@@ -0,0 +1,250 @@
+use crate::instruction::Opcode;
+use crate::vm::decoder::decode;
+use crate::vm::VmError;
+use std::collections::HashSet;
+use std::fmt;
+
+// A problem found in a program before running it
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyError {
+    // The program ends in the middle of the instruction
+    TruncatedInstruction { pc: usize },
+    // A register operand is not below 32
+    InvalidRegister { pc: usize, index: u8 },
+    // The byte decodes to Opcode::IGL
+    UnknownOpcode { pc: usize, byte: u8 },
+    // The jump lands neither on the start of an instruction nor on the end of the program
+    InvalidJumpTarget { pc: usize, target: i32 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::TruncatedInstruction { pc } => {
+                write!(f, "truncated instruction at {}", pc)
+            }
+            VerifyError::InvalidRegister { pc, index } => {
+                write!(f, "invalid register ${} at {}", index, pc)
+            }
+            VerifyError::UnknownOpcode { pc, byte } => {
+                write!(f, "unknown opcode {} at {}", byte, pc)
+            }
+            VerifyError::InvalidJumpTarget { pc, target } => {
+                write!(
+                    f,
+                    "jump at {} to {}, which is not an instruction",
+                    pc, target
+                )
+            }
+        }
+    }
+}
+
+// Checks a program without running it: every instruction must be complete, use known
+// opcodes and valid registers, and absolute jumps (JMP, JEQ, JNEQ, CALL), interrupt handlers
+// set by IVEC and processes started by SPAWN must land on the start of an instruction or at
+// the end of the program. Jump targets held in a register can
+// only be checked when it was set by a LOAD in the same straight-line code as the jump
+pub fn verify(program: &[u8]) -> Result<(), Vec<VerifyError>> {
+    let mut errors = vec![];
+    let mut boundaries = HashSet::new();
+    // Jumps whose target is known, checked once all the boundaries are known
+    let mut jumps = vec![];
+    // Value LOAD'ed in each register since the last jump
+    let mut known: [Option<i32>; 32] = [None; 32];
+
+    let mut pc = 0;
+    while pc < program.len() {
+        boundaries.insert(pc);
+        let opcode = Opcode::from(program[pc]);
+        let len = 1 + opcode.operand_bytes();
+        let instruction = match decode(program, pc) {
+            Ok(instruction) => instruction,
+            Err(VmError::InvalidRegister { index, .. }) => {
+                errors.push(VerifyError::InvalidRegister { pc, index });
+                known = [None; 32];
+                pc += len;
+                continue;
+            }
+            Err(_) => {
+                errors.push(VerifyError::TruncatedInstruction { pc });
+                break;
+            }
+        };
+
+        let registers = &instruction.registers[..opcode.register_operands()];
+        match opcode {
+            Opcode::IGL => errors.push(VerifyError::UnknownOpcode {
+                pc,
+                byte: program[pc],
+            }),
+            Opcode::LOAD => known[registers[0]] = Some(instruction.immediate as i32),
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => {
+                if let Some(target) = known[registers[0]] {
+                    jumps.push((pc, target));
+                }
+            }
+            Opcode::CALL | Opcode::IVEC => jumps.push((pc, instruction.immediate as i32)),
+            Opcode::SPAWN => {
+                known[registers[0]] = None;
+                jumps.push((pc, instruction.immediate as i32));
+            }
+            _ => {
+                // Any register operand may have been written
+                for &register in registers {
+                    known[register] = None;
+                }
+            }
+        }
+        // Code after a jump or a halt can be reached from anywhere, and a host function
+        // can change any register
+        if let Opcode::HLT
+        | Opcode::JMP
+        | Opcode::JMPF
+        | Opcode::JMPB
+        | Opcode::JEQ
+        | Opcode::JNEQ
+        | Opcode::CALL
+        | Opcode::RET
+        | Opcode::IRET
+        | Opcode::SYSCALL = opcode
+        {
+            known = [None; 32];
+        }
+        pc += len;
+    }
+    boundaries.insert(program.len());
+
+    for (pc, target) in jumps {
+        let valid = usize::try_from(target).is_ok_and(|t| boundaries.contains(&t));
+        if !valid {
+            errors.push(VerifyError::InvalidJumpTarget { pc, target });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_valid_program() {
+        let program = vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            8,
+            Opcode::JMP as u8,
+            1,
+            Opcode::HLT as u8,
+            0,
+            Opcode::INC as u8,
+            2,
+            0,
+            0,
+        ];
+        assert_eq!(verify(&program), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let program = vec![
+            Opcode::ADD as u8,
+            1,
+            2,
+            32,
+            200,
+            Opcode::LOAD as u8,
+            1,
+            0,
+            7,
+            Opcode::JEQ as u8,
+            1,
+            Opcode::LOAD as u8,
+            1,
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                VerifyError::InvalidRegister { pc: 0, index: 32 },
+                VerifyError::UnknownOpcode { pc: 4, byte: 200 },
+                VerifyError::TruncatedInstruction { pc: 11 },
+                VerifyError::InvalidJumpTarget { pc: 9, target: 7 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_verify_call() {
+        let program = vec![
+            Opcode::CALL as u8,
+            0,
+            8,
+            0,
+            Opcode::CALL as u8,
+            0,
+            6,
+            0,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![VerifyError::InvalidJumpTarget { pc: 4, target: 6 }])
+        );
+    }
+
+    #[test]
+    fn test_verify_unknown_targets() {
+        // The target is computed, so it cannot be checked
+        let program = vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            7,
+            Opcode::INC as u8,
+            1,
+            0,
+            0,
+            Opcode::JMP as u8,
+            1,
+        ];
+        assert_eq!(verify(&program), Ok(()));
+
+        // The JMP after the HLT is only reached by jumping to it, with any value in $1
+        let program = vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            3,
+            Opcode::HLT as u8,
+            Opcode::JMP as u8,
+            1,
+        ];
+        assert_eq!(verify(&program), Ok(()));
+
+        // The host function may have changed $1
+        let program = vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            3,
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::JMP as u8,
+            1,
+        ];
+        assert_eq!(verify(&program), Ok(()));
+    }
+}